@@ -4,6 +4,33 @@
 #include <ostream>
 #include <new>
 
+/// Which model axis points up.
+enum class UpAxis {
+  Z,
+  Y,
+};
+
+enum class Projection {
+  Perspective,
+  Orthographic,
+};
+
+/// Where the camera looks at the model from.
+///
+/// The camera always points at the center of the model. Angles are in degrees.
+/// An azimuth of 0 looks at the front of the model (from -Y when Z is up), and positive
+/// values move the camera around to the right. Positive elevations look down on the model.
+struct Camera {
+  float azimuth;
+  float elevation;
+  float roll;
+  float distance;
+  float fov;
+  UpAxis up;
+  Projection projection;
+  float padding;
+};
+
 #ifdef __cplusplus
 extern "C" {
 #endif
@@ -34,6 +61,42 @@ bool render_to_buffer(uint8_t *buf_ptr,
                       uint32_t height,
                       const char *model_filename_c);
 
+/// Same as [`render_to_buffer`], but looking at the model from `camera`.
+///
+/// `camera` may be null to use the default view. [`camera_preset`] fills in a camera
+/// for one of the named views. Returns `false` if the camera can't be rendered with, like
+/// one with a field of view of 180 degrees or more.
+///
+/// # Example in C
+/// ```c
+/// Camera camera;
+/// camera_preset("front", &camera);
+/// camera.elevation = 15.0;
+///
+/// render_to_buffer_with_camera(buf_ptr, width, height, model_filename_c, &camera);
+/// ```
+///
+/// # Safety
+///
+/// * `buf_ptr` _must_ point to a valid initialized buffer, at least `width * height * 4` bytes long.
+/// * `model_filename_c` must point to a valid null-terminated string.
+/// * `camera` must be null or point to a valid `Camera`.
+bool render_to_buffer_with_camera(uint8_t *buf_ptr,
+                                  uint32_t width,
+                                  uint32_t height,
+                                  const char *model_filename_c,
+                                  const Camera *camera);
+
+/// Fills `camera` with one of the named views: iso, front, back, left, right, top or bottom.
+///
+/// Returns `false` and leaves `camera` unchanged if the name is not known.
+///
+/// # Safety
+///
+/// * `name_c` must point to a valid null-terminated string.
+/// * `camera` must point to a valid `Camera`.
+bool camera_preset(const char *name_c, Camera *camera);
+
 #ifdef __cplusplus
 } // extern "C"
 #endif
@@ -0,0 +1,229 @@
+// Animations
+// ==========
+//
+// Turntable frames are encoded into a single looping animation. The image format picks the
+// container: PNG becomes APNG, GIF stays GIF and WebP becomes animated WebP.
+
+use crate::error::Error;
+use crate::thumbnail::ThumbInfo;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::webp::WebPEncoder;
+use image::error::{EncodingError, ParameterError, ParameterErrorKind};
+use image::{Delay, DynamicImage, ExtendedColorType, Frame, ImageError, ImageFormat};
+
+// Speed of the GIF color quantizer, from 1 (best colors) to 30 (fastest)
+const GIF_SPEED: i32 = 10;
+
+/// Encodes `frames` as an animation that loops forever, showing each frame for `delay` milliseconds.
+/// APNG files get the details of the model file in text chunks if there are any.
+pub(crate) fn encode(
+    frames: &[DynamicImage],
+    format: ImageFormat,
+    delay: u16,
+    info: Option<&ThumbInfo>,
+) -> Result<Vec<u8>, Error> {
+    match format {
+        ImageFormat::Png => encode_apng(frames, delay, info),
+        ImageFormat::Gif => encode_gif(frames, delay),
+        ImageFormat::WebP => encode_webp(frames, delay),
+        _ => Err(Error::Encode(ImageError::Parameter(
+            ParameterError::from_kind(ParameterErrorKind::Generic(format!(
+                "{:?} images can't be animated. Use PNG, GIF or WebP instead.",
+                format
+            ))),
+        ))),
+    }
+}
+
+fn encode_gif(frames: &[DynamicImage], delay: u16) -> Result<Vec<u8>, Error> {
+    let mut buff = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut buff, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames.iter().map(|img| {
+            Frame::from_parts(
+                img.to_rgba8(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(delay as u32, 1),
+            )
+        }))?;
+    }
+    Ok(buff)
+}
+
+fn encode_apng(
+    frames: &[DynamicImage],
+    delay: u16,
+    info: Option<&ThumbInfo>,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = size(frames);
+    let mut buff = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buff, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    // Every frame replaces the whole image, so transparent pixels don't show the frame before
+    encoder.set_blend_op(png::BlendOp::Source)?;
+    encoder.set_frame_delay(delay, 1000)?;
+    for (keyword, text) in info.iter().flat_map(|info| info.text_chunks()) {
+        encoder.add_text_chunk(keyword.to_string(), text)?;
+    }
+    let mut writer = encoder.write_header()?;
+    for img in frames {
+        writer.write_image_data(img.to_rgba8().as_raw())?;
+    }
+    writer.finish()?;
+    Ok(buff)
+}
+
+// The WebP encoder only writes still images, so each frame is encoded on its own and its
+// image data is moved into an animation frame (ANMF) chunk of an extended WebP file.
+// https://developers.google.com/speed/webp/docs/riff_container
+fn encode_webp(frames: &[DynamicImage], delay: u16) -> Result<Vec<u8>, Error> {
+    let (width, height) = size(frames);
+    let mut body = Vec::new();
+
+    // Animated, with alpha
+    let mut vp8x = vec![0x02 | 0x10, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    write_chunk(&mut body, b"VP8X", &vp8x);
+    // Transparent background, loop forever
+    write_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for img in frames {
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still).encode(
+            img.to_rgba8().as_raw(),
+            width,
+            height,
+            ExtendedColorType::Rgba8,
+        )?;
+
+        // Offset of 0, size of the canvas and the frame duration
+        let mut anmf = vec![0; 6];
+        anmf.extend_from_slice(&u24(width - 1));
+        anmf.extend_from_slice(&u24(height - 1));
+        anmf.extend_from_slice(&u24(delay as u32));
+        // Replace the canvas instead of blending over it, and don't dispose
+        anmf.push(0x02);
+        anmf.extend_from_slice(webp_image_data(&still)?);
+        write_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut buff = Vec::with_capacity(body.len() + 12);
+    buff.extend_from_slice(b"RIFF");
+    buff.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    buff.extend_from_slice(b"WEBP");
+    buff.extend_from_slice(&body);
+    Ok(buff)
+}
+
+// The image chunk of a simple lossless WebP file, including its header and padding
+fn webp_image_data(file: &[u8]) -> Result<&[u8], Error> {
+    let mut chunks = file.get(12..).unwrap_or_default();
+    while chunks.len() >= 8 {
+        let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        // Chunks are padded to an even size, which is kept when they are moved into a frame
+        let end = (8 + len + (len & 1)).min(chunks.len());
+        if &chunks[..4] == b"VP8L" {
+            return Ok(&chunks[..end]);
+        }
+        chunks = &chunks[end..];
+    }
+    Err(Error::Encode(ImageError::Encoding(EncodingError::new(
+        ImageFormat::WebP.into(),
+        "Frame has no VP8L chunk",
+    ))))
+}
+
+fn write_chunk(buff: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    buff.extend_from_slice(fourcc);
+    buff.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buff.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buff.push(0);
+    }
+}
+
+fn u24(x: u32) -> [u8; 3] {
+    let [a, b, c, _] = x.to_le_bytes();
+    [a, b, c]
+}
+
+fn size(frames: &[DynamicImage]) -> (u32, u32) {
+    frames
+        .first()
+        .map(|img| (img.width(), img.height()))
+        .unwrap_or((1, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifDecoder;
+    use image::codecs::webp::WebPDecoder;
+    use image::{AnimationDecoder, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    // Frames with a square moving across a transparent background
+    fn frames() -> Vec<DynamicImage> {
+        (0..4)
+            .map(|i| {
+                let mut img = RgbaImage::new(16, 12);
+                for y in 4..8 {
+                    for x in i * 3..i * 3 + 4 {
+                        img.put_pixel(x, y, Rgba([200, 50, 10, 255]));
+                    }
+                }
+                DynamicImage::ImageRgba8(img)
+            })
+            .collect()
+    }
+
+    fn check_frames(decoded: Vec<Frame>, delay: u32) {
+        let expected = frames();
+        assert_eq!(expected.len(), decoded.len());
+        for (frame, img) in decoded.into_iter().zip(&expected) {
+            assert_eq!(Delay::from_numer_denom_ms(delay, 1), frame.delay());
+            assert_eq!(&img.to_rgba8(), frame.buffer());
+        }
+    }
+
+    #[test]
+    fn gif() {
+        let data = encode(&frames(), ImageFormat::Gif, 80, None).unwrap();
+        let decoder = GifDecoder::new(Cursor::new(data)).unwrap();
+        check_frames(decoder.into_frames().collect_frames().unwrap(), 80);
+    }
+
+    #[test]
+    fn webp() {
+        let data = encode(&frames(), ImageFormat::WebP, 80, None).unwrap();
+        let decoder = WebPDecoder::new(Cursor::new(data)).unwrap();
+        assert!(decoder.has_animation());
+        check_frames(decoder.into_frames().collect_frames().unwrap(), 80);
+    }
+
+    #[test]
+    fn apng() {
+        let data = encode(&frames(), ImageFormat::Png, 80, None).unwrap();
+        let mut reader = png::Decoder::new(Cursor::new(data)).read_info().unwrap();
+        let actl = reader.info().animation_control.unwrap();
+        assert_eq!(4, actl.num_frames);
+        let mut buf = vec![0; reader.output_buffer_size()];
+        for img in frames() {
+            reader.next_frame(&mut buf).unwrap();
+            let fctl = reader.info().frame_control.unwrap();
+            assert_eq!((80, 1000), (fctl.delay_num, fctl.delay_den));
+            assert_eq!(img.to_rgba8().as_raw(), &buf);
+        }
+    }
+
+    #[test]
+    fn still_formats() {
+        assert!(encode(&frames(), ImageFormat::Jpeg, 80, None).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+// Batch Mode
+// ==========
+//
+// Renders thumbnails for many model files in a single process, sharing one Renderer
+// (and so one GL context) between all of them.
+
+use crate::config::{BatchConfig, Config, RenderOptions};
+use crate::error::Error;
+use crate::mesh::MODEL_EXTENSIONS;
+use crate::{load_mesh, thumb_info, write_animation, write_image, Renderer};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io};
+
+/// A single model file and the thumbnail it will be rendered to.
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+pub enum Outcome {
+    Rendered,
+    /// The thumbnail is newer than the model file, so it was not rendered again.
+    UpToDate,
+    Failed(Error),
+    /// Loading the model failed before and it hasn't changed since, so it was not tried again.
+    FailedBefore,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub rendered: usize,
+    pub up_to_date: usize,
+    pub failed: usize,
+    /// Models that failed in an earlier run and were not tried again.
+    pub failed_before: usize,
+}
+
+/// Renders every model matched by `batch`.
+///
+/// `report` is called once for each model with the result of rendering it. Errors for single models
+/// do not stop the batch, they are counted in the returned summary instead. Models that would get
+/// the same thumbnail as another one fail without rendering either of them.
+pub fn run<F>(config: &Config, batch: &BatchConfig, mut report: F) -> Result<Summary, Error>
+where
+    F: FnMut(&Job, &Outcome),
+{
+    let jobs = collect_jobs(batch)?;
+    let collisions = find_collisions(&jobs);
+    let mut renderer = Renderer::new(config)?;
+    let options = RenderOptions::from(config);
+    let mut summary = Summary::default();
+
+    for (i, job) in jobs.iter().enumerate() {
+        let outcome = if let Some(other) = collisions.get(&i) {
+            summary.failed += 1;
+            Outcome::Failed(Error::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is the thumbnail of {} too, change the template to tell them apart",
+                    job.output.display(),
+                    other.display()
+                ),
+            )))
+        } else if !batch.force && up_to_date(job) {
+            summary.up_to_date += 1;
+            Outcome::UpToDate
+        } else {
+            match render_job(config, &mut renderer, &options, job) {
+                Ok(()) => {
+                    summary.rendered += 1;
+                    Outcome::Rendered
+                }
+                Err(e) => {
+                    summary.failed += 1;
+                    Outcome::Failed(e)
+                }
+            }
+        };
+        report(job, &outcome);
+    }
+
+    Ok(summary)
+}
+
+fn render_job(
+    config: &Config,
+    renderer: &mut Renderer,
+    options: &RenderOptions,
+    job: &Job,
+) -> Result<(), Error> {
+    let input = job.input.to_str().ok_or_else(invalid_file_name)?;
+    let output = job.output.to_str().ok_or_else(invalid_file_name)?;
+    let info = thumb_info(input, config);
+    let mesh = load_mesh(input, config)?;
+    match config.turntable {
+        Some(turntable) => {
+            let frames = renderer.render_turntable(&mesh, options, turntable.frames)?;
+            create_parent(&job.output)?;
+            write_animation(
+                &frames,
+                output,
+                &config.format,
+                turntable.frame_delay,
+                info.as_ref(),
+            )
+        }
+        None => {
+            let img = match config.sheet {
+                Some(ref sheet) => renderer.render_sheet(&mesh, options, sheet)?,
+                None => renderer.render(&mesh, options)?,
+            };
+            create_parent(&job.output)?;
+            write_image(&img, output, &config.format, info.as_ref())
+        }
+    }
+}
+
+// Directory the thumbnail goes in, created only once there is something to write
+fn create_parent(output: &Path) -> Result<(), Error> {
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn invalid_file_name() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "File name is not valid unicode",
+    ))
+}
+
+// Jobs that share their output with another one, with the input of that other job
+fn find_collisions(jobs: &[Job]) -> HashMap<usize, &Path> {
+    let mut outputs: HashMap<&Path, Vec<usize>> = HashMap::new();
+    for (i, job) in jobs.iter().enumerate() {
+        outputs.entry(&job.output).or_default().push(i);
+    }
+    let mut collisions = HashMap::new();
+    for same in outputs.values().filter(|same| same.len() > 1) {
+        for (n, &i) in same.iter().enumerate() {
+            let other = same[if n == 0 { 1 } else { 0 }];
+            collisions.insert(i, jobs[other].input.as_path());
+        }
+    }
+    collisions
+}
+
+fn up_to_date(job: &Job) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+    match (modified(&job.input), modified(&job.output)) {
+        (Ok(input), Ok(output)) => output >= input,
+        _ => false,
+    }
+}
+
+/// Expands the inputs of `batch` into a list of jobs.
+///
+/// Inputs can be model files, directories, or glob patterns. Directories are searched for files
+/// with a supported extension, recursively if `batch.recursive` is set.
+pub fn collect_jobs(batch: &BatchConfig) -> Result<Vec<Job>, Error> {
+    let output_dir = Path::new(&batch.output_dir);
+    let jobs = collect_models(&batch.inputs, batch.recursive)?
+        .into_iter()
+        .map(|(model, base)| Job {
+            output: output_dir.join(expand_template(&batch.template, &model, &base)),
+            input: model,
+        })
+        .collect();
+    Ok(jobs)
+}
+
+// Model files matched by `inputs`, each paired with the directory it was found relative to for
+// the {dir} field
+pub(crate) fn collect_models(
+    inputs: &[String],
+    recursive: bool,
+) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let mut models: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+
+        if is_glob(input) {
+            let base = glob_base(input);
+            let mut matched = false;
+            let entries = glob::glob(input)
+                .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+            for entry in entries {
+                let entry = entry.map_err(io::Error::from)?;
+                matched = true;
+                if entry.is_dir() {
+                    find_models(&entry, &base, recursive, &mut models)?;
+                } else if is_model_file(&entry) {
+                    models.push((entry, base.clone()));
+                }
+            }
+            if !matched {
+                warn!("No files match {}", input);
+            }
+        } else if path.is_dir() {
+            find_models(path, path, recursive, &mut models)?;
+        } else {
+            // Named explicitly, so don't filter on extension
+            let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            models.push((path.to_path_buf(), base));
+        }
+    }
+
+    Ok(models)
+}
+
+fn find_models(
+    dir: &Path,
+    base: &Path,
+    recursive: bool,
+    models: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), Error> {
+    let max_depth = if recursive { usize::MAX } else { 1 };
+    let walker = walkdir::WalkDir::new(dir)
+        .max_depth(max_depth)
+        .sort_by_file_name();
+    for entry in walker {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() && is_model_file(entry.path()) {
+            models.push((entry.into_path(), base.to_path_buf()));
+        }
+    }
+    Ok(())
+}
+
+fn is_model_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| MODEL_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+// Leading part of a glob pattern that doesn't contain any wildcards
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+/// Builds the thumbnail file name for `model` from `template`.
+///
+/// | Field    | Replaced with                                        |
+/// | -------- | ---------------------------------------------------- |
+/// | `{stem}` | File name without extension                          |
+/// | `{name}` | File name with extension                             |
+/// | `{ext}`  | Extension                                            |
+/// | `{dir}`  | Directory of the model relative to `base`            |
+///
+/// The result is always a relative path, so it can't point outside the output directory.
+pub fn expand_template(template: &str, model: &Path, base: &Path) -> PathBuf {
+    fn field(s: Option<&OsStr>) -> String {
+        s.map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    let dir = model
+        .parent()
+        .and_then(|p| p.strip_prefix(base).ok())
+        .map(|p| p.to_string_lossy())
+        .unwrap_or_default();
+    let expanded = template
+        .replace("{stem}", &field(model.file_stem()))
+        .replace("{name}", &field(model.file_name()))
+        .replace("{ext}", &field(model.extension()))
+        .replace("{dir}", &dir);
+    Path::new(&expanded)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Backend;
+
+    #[test]
+    fn template() {
+        let model = Path::new("models/parts/gear.stl");
+        let base = Path::new("models");
+        assert_eq!(
+            PathBuf::from("gear.png"),
+            expand_template("{stem}.png", model, base)
+        );
+        assert_eq!(
+            PathBuf::from("parts/gear.stl.jpg"),
+            expand_template("{dir}/{name}.jpg", model, base)
+        );
+        // Empty directory doesn't turn into an absolute path
+        assert_eq!(
+            PathBuf::from("gear-stl.png"),
+            expand_template("{dir}/{stem}-{ext}.png", model, Path::new("models/parts"))
+        );
+    }
+
+    #[test]
+    fn collect_directory() {
+        let batch = BatchConfig {
+            inputs: vec!["test_data".to_string()],
+            output_dir: "thumbs".to_string(),
+            template: "{name}.png".to_string(),
+            recursive: false,
+            force: false,
+        };
+        let jobs = collect_jobs(&batch).unwrap();
+        assert!(find_collisions(&jobs).is_empty());
+        let outputs: Vec<PathBuf> = jobs.into_iter().map(|j| j.output).collect();
+        assert!(outputs.contains(&PathBuf::from("thumbs/cube.stl.png")));
+        assert!(outputs.contains(&PathBuf::from("thumbs/cube.obj.png")));
+        assert!(outputs.contains(&PathBuf::from("thumbs/sphere.stl.png")));
+        // README.md is not a model
+        assert!(!outputs.contains(&PathBuf::from("thumbs/README.md.png")));
+    }
+
+    #[test]
+    fn colliding_outputs() {
+        let dir = std::env::temp_dir().join(format!("stl-thumb-batch-{}", std::process::id()));
+        let batch = BatchConfig {
+            inputs: vec![
+                "test_data/cube.stl".to_string(),
+                "test_data/cube.obj".to_string(),
+                "test_data/sphere.stl".to_string(),
+            ],
+            output_dir: dir.to_string_lossy().into_owned(),
+            template: "{stem}.png".to_string(),
+            recursive: false,
+            force: false,
+        };
+        let config = Config {
+            backend: Backend::Software,
+            ..Default::default()
+        };
+        let mut failures = Vec::new();
+        let summary = run(&config, &batch, |job, outcome| {
+            if let Outcome::Failed(e) = outcome {
+                failures.push((job.input.clone(), e.to_string()));
+            }
+        })
+        .unwrap();
+
+        assert_eq!((1, 2), (summary.rendered, summary.failed));
+        assert_eq!(PathBuf::from("test_data/cube.stl"), failures[0].0);
+        assert!(
+            failures[0].1.contains("test_data/cube.obj"),
+            "{}",
+            failures[0].1
+        );
+        assert!(
+            failures[1].1.contains("test_data/cube.stl"),
+            "{}",
+            failures[1].1
+        );
+        assert!(!dir.join("cube.png").exists());
+        assert!(dir.join("sphere.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+// Thumbnail Cache
+// ===============
+//
+// Renders thumbnails straight into the cache that file managers read from, so they don't have
+// to call a thumbnailer for every file. Thumbnails are named after the MD5 of the model's URI
+// and are checked against the model with the details in their text chunks.
+// https://specifications.freedesktop.org/thumbnail-spec/latest/
+
+use crate::batch::{collect_models, Job, Outcome, Summary};
+use crate::config::{CacheConfig, CacheSize, Config, RenderOptions};
+use crate::error::Error;
+use crate::mesh::Mesh;
+use crate::thumbnail::ThumbInfo;
+use crate::{encode_image, load_mesh, uri, Renderer};
+use image::{DynamicImage, ImageFormat};
+use md5::{Digest, Md5};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Renders every model matched by `cache` into the thumbnail cache, in each of its sizes.
+///
+/// `report` is called once for each thumbnail with the result of rendering it. A model that
+/// can't be loaded is recorded in the cache as a failure and reported only once, with the
+/// failure record as the output. Errors for single models do not stop the run, they are
+/// counted in the returned summary instead. Models that failed in an earlier run are counted
+/// apart from new failures.
+pub fn run<F>(config: &Config, cache: &CacheConfig, mut report: F) -> Result<Summary, Error>
+where
+    F: FnMut(&Job, &Outcome),
+{
+    if config.turntable.is_some() {
+        warn!("The thumbnail cache only holds still images, so no turntables are rendered");
+    }
+    let models = collect_models(&cache.inputs, cache.recursive)?;
+    let mut renderer = Renderer::new(config)?;
+    let mut summary = Summary::default();
+
+    for (model, _) in models {
+        let mut record = |job: Job, outcome: Outcome| {
+            match outcome {
+                Outcome::Rendered => summary.rendered += 1,
+                Outcome::UpToDate => summary.up_to_date += 1,
+                Outcome::Failed(_) => summary.failed += 1,
+                Outcome::FailedBefore => summary.failed_before += 1,
+            }
+            report(&job, &outcome);
+        };
+        // File managers look thumbnails up by the URI of the file with symlinks resolved
+        match fs::canonicalize(&model)
+            .map_err(Error::from)
+            .and_then(|path| uri::file_uri(&path))
+        {
+            Ok(uri) => cache_model(config, cache, &mut renderer, &model, &uri, &mut record),
+            Err(e) => record(
+                Job {
+                    input: model,
+                    output: PathBuf::new(),
+                },
+                Outcome::Failed(e),
+            ),
+        }
+    }
+
+    Ok(summary)
+}
+
+// Renders the model at `uri` into the cache. `record` is called like the `report` function of
+// run, `model` is only used as the input of the jobs it is given.
+pub(crate) fn cache_model<F>(
+    config: &Config,
+    cache: &CacheConfig,
+    renderer: &mut Renderer,
+    model: &Path,
+    uri: &str,
+    record: &mut F,
+) where
+    F: FnMut(Job, Outcome),
+{
+    let job = |output: PathBuf| Job {
+        input: model.to_path_buf(),
+        output,
+    };
+    let name = thumbnail_name(uri);
+    let failure = cache.dir.join("fail").join(failure_dir()).join(&name);
+    // Read before rendering, so a model that changes in the meantime gets a new thumbnail later
+    let info = match ThumbInfo::for_model(uri, config.input_format) {
+        Ok(info) => info,
+        Err(e) => {
+            // Files that aren't models at all are recorded as failures too
+            if let Ok(info) = ThumbInfo::for_file(uri, "application/octet-stream") {
+                if !cache.force && is_current(&failure, &info) {
+                    return record(job(failure), Outcome::FailedBefore);
+                }
+                write_failure(&failure, &info);
+            }
+            return record(job(failure), Outcome::Failed(e));
+        }
+    };
+    if !cache.force && is_current(&failure, &info) {
+        return record(job(failure), Outcome::FailedBefore);
+    }
+
+    let mut mesh: Option<Mesh> = None;
+    for &size in &cache.sizes {
+        let output = cache.dir.join(size.name()).join(&name);
+        if !cache.force && is_current(&output, &info) {
+            record(job(output), Outcome::UpToDate);
+            continue;
+        }
+
+        let mesh = match mesh {
+            Some(ref mesh) => mesh,
+            None => match load_mesh(uri, config) {
+                Ok(loaded) => {
+                    remove_failure(&failure);
+                    mesh.insert(loaded)
+                }
+                Err(e) => {
+                    write_failure(&failure, &info);
+                    return record(job(failure), Outcome::Failed(e));
+                }
+            },
+        };
+        let outcome = match render_thumbnail(config, renderer, mesh, size, &info, &output) {
+            Ok(()) => Outcome::Rendered,
+            Err(e) => Outcome::Failed(e),
+        };
+        record(job(output), outcome);
+    }
+}
+
+fn render_thumbnail(
+    config: &Config,
+    renderer: &mut Renderer,
+    mesh: &Mesh,
+    size: CacheSize,
+    info: &ThumbInfo,
+    output: &Path,
+) -> Result<(), Error> {
+    let options = RenderOptions {
+        width: size.pixels(),
+        height: size.pixels(),
+        ..RenderOptions::from(config)
+    };
+    let img = match config.sheet {
+        Some(ref sheet) => renderer.render_sheet(mesh, &options, sheet)?,
+        None => renderer.render(mesh, &options)?,
+    };
+    write_atomic(output, &encode_image(&img, &ImageFormat::Png, Some(info))?)
+}
+
+/// File name of the thumbnail for a URI, the same for every size.
+pub fn thumbnail_name(uri: &str) -> String {
+    let hash = Md5::digest(uri.as_bytes());
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.png", hex)
+}
+
+// Failures are kept per program version, as a later one may be able to read the model
+fn failure_dir() -> String {
+    format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+// Whether the PNG at `path` was made from the model as it is now
+fn is_current(path: &Path, info: &ThumbInfo) -> bool {
+    let reader = match fs::File::open(path)
+        .map(|file| png::Decoder::new(io::BufReader::new(file)).read_info())
+    {
+        Ok(Ok(reader)) => reader,
+        _ => return false,
+    };
+    let text = &reader.info().uncompressed_latin1_text;
+    let field = |keyword: &str| {
+        text.iter()
+            .find(|chunk| chunk.keyword == keyword)
+            .map(|chunk| chunk.text.as_str())
+    };
+    field("Thumb::URI") == Some(info.uri.as_str())
+        && field("Thumb::MTime") == Some(info.mtime.to_string().as_str())
+}
+
+// A failure is an empty image with the details of the model it was made for
+fn write_failure(path: &Path, info: &ThumbInfo) {
+    let img = DynamicImage::new_rgba8(1, 1);
+    let result = encode_image(&img, &ImageFormat::Png, Some(info))
+        .and_then(|data| write_atomic(path, &data));
+    if let Err(e) = result {
+        warn!("Unable to record failure for {}. Reason: {}", info.uri, e);
+    }
+}
+
+fn remove_failure(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => warn!("Unable to remove {}. Reason: {}", path.display(), e),
+    }
+}
+
+// The data is written to a temporary file next to `path` that is then renamed, so file
+// managers never read a partly written thumbnail. Only the user can read the cache.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut dirs = fs::DirBuilder::new();
+    dirs.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut dirs, 0o700);
+    if let Some(dir) = path.parent() {
+        dirs.create(dir)?;
+    }
+
+    let temp = path.with_extension(format!("png.{}.tmp", std::process::id()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options
+        .open(&temp)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Backend;
+
+    #[test]
+    fn name() {
+        // Example from the thumbnail spec
+        assert_eq!(
+            "c6ee772d9e49320e97ec29a7eb5b1697.png",
+            thumbnail_name("file:///home/jens/photos/me.png")
+        );
+    }
+
+    #[test]
+    fn cache_models() {
+        let dir = std::env::temp_dir().join(format!("stl-thumb-cache-{}", std::process::id()));
+        let broken = dir.join("broken.stl");
+        let notes = dir.join("notes.txt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&broken, "not a model").unwrap();
+        fs::write(&notes, "not a model either").unwrap();
+        let config = Config {
+            backend: Backend::Software,
+            ..Default::default()
+        };
+        let cache = CacheConfig {
+            inputs: vec![
+                "test_data/cube.stl".to_string(),
+                broken.to_string_lossy().into_owned(),
+                notes.to_string_lossy().into_owned(),
+            ],
+            dir: dir.join("thumbnails"),
+            sizes: vec![CacheSize::Normal, CacheSize::Large],
+            recursive: false,
+            force: false,
+        };
+        let run_cache = || {
+            let mut outcomes = Vec::new();
+            let summary = run(&config, &cache, |job, outcome| {
+                outcomes.push((job.output.clone(), std::mem::discriminant(outcome)))
+            })
+            .unwrap();
+            (outcomes, summary)
+        };
+
+        let (first, summary) = run_cache();
+        let uri = uri::file_uri(&fs::canonicalize("test_data/cube.stl").unwrap()).unwrap();
+        let thumbnail = cache.dir.join("large").join(thumbnail_name(&uri));
+        let failure = cache.dir.join("fail").join(failure_dir());
+        assert_eq!(4, first.len());
+        assert_eq!(
+            (
+                thumbnail.clone(),
+                std::mem::discriminant(&Outcome::Rendered)
+            ),
+            first[1]
+        );
+        assert_eq!(256, image::open(&thumbnail).unwrap().width());
+        for (output, _) in &first[2..] {
+            assert_eq!(failure, output.parent().unwrap());
+            assert!(output.exists());
+        }
+        assert_eq!(2, summary.failed);
+
+        // Nothing changed, so nothing is rendered or tried again, and nothing new failed
+        let (second, summary) = run_cache();
+        assert_eq!(std::mem::discriminant(&Outcome::UpToDate), second[1].1);
+        for (_, outcome) in &second[2..] {
+            assert_eq!(std::mem::discriminant(&Outcome::FailedBefore), *outcome);
+        }
+        assert_eq!((0, 2), (summary.failed, summary.failed_before));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,334 @@
+use crate::format::ModelFormat;
 use image::ImageFormat;
 use std::f32;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Clone)]
+// Strength of the ambient light, for models that only specify how colors reflect light
+pub const AMBIENT_LIGHT: f32 = 0.25;
+
+// How much of the light the shadow on the ground plane blocks
+pub const SHADOW_OPACITY: f32 = 0.35;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Material {
     pub ambient: [f32; 3],
     pub diffuse: [f32; 3],
     pub specular: [f32; 3],
 }
 
+impl Material {
+    /// Phong material for a model that only specifies a single color.
+    /// Ambient is a darker shade of the color and highlights are white, like the default material.
+    pub fn from_color(color: [f32; 3]) -> Material {
+        Material {
+            ambient: color.map(|c| c * AMBIENT_LIGHT),
+            diffuse: color,
+            specular: [1.0, 1.0, 1.0],
+        }
+    }
+}
+
+/// Which model axis points up.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UpAxis {
+    // STL and 3MF
+    Z,
+    // glTF and most OBJ exports
+    Y,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    // Parallel edges stay parallel, sizes don't depend on the distance to the camera
+    Orthographic,
+}
+
+/// Where the camera looks at the model from.
+///
+/// The camera always points at the center of the model. Angles are in degrees.
+/// An azimuth of 0 looks at the front of the model (from -Y when Z is up), and positive
+/// values move the camera around to the right. Positive elevations look down on the model.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub azimuth: f32,
+    pub elevation: f32,
+    // Rotation of the image around the viewing direction
+    pub roll: f32,
+    // Distance from the center, in units of the scaled model which fits a 2 x 2 x 2 box.
+    // 0 moves the camera as close as it can get while still showing the whole model.
+    pub distance: f32,
+    // Vertical field of view. Not used by orthographic projections, which always fit the model.
+    pub fov: f32,
+    pub up: UpAxis,
+    pub projection: Projection,
+    // Empty space left around the model, as a percentage of the image size on each side.
+    // Used when the distance is 0, and for orthographic projections.
+    pub padding: f32,
+}
+
+// Names accepted by Camera::preset
+pub const CAMERA_PRESETS: &[&str] = &["iso", "front", "back", "left", "right", "top", "bottom"];
+
+impl Default for Camera {
+    fn default() -> Self {
+        // Looking from the direction of (2, -4, 2)
+        Camera {
+            azimuth: 26.565,
+            elevation: 24.095,
+            roll: 0.0,
+            distance: 0.0,
+            fov: 30.0,
+            up: UpAxis::Z,
+            projection: Projection::Perspective,
+            padding: 5.0,
+        }
+    }
+}
+
+impl Camera {
+    /// Checks that the camera can be rendered with. Fields of view have to be between 0 and 180
+    /// degrees, distances 0 or more and padding at least 0 and below 50%.
+    pub fn check(&self) -> Result<(), String> {
+        if ![self.azimuth, self.elevation, self.roll]
+            .iter()
+            .all(|a| a.is_finite())
+        {
+            return Err("Camera angles must be finite".to_string());
+        }
+        // 0 is fitting the model, so unlike on the command line it is a valid distance here
+        if !(self.distance == 0.0 || valid_distance(self.distance)) {
+            return Err(format!("Invalid camera distance {}", self.distance));
+        }
+        if !valid_fov(self.fov) {
+            return Err(format!("Invalid field of view {}", self.fov));
+        }
+        if !valid_padding(self.padding) {
+            return Err(format!("Invalid padding {}", self.padding));
+        }
+        Ok(())
+    }
+
+    /// Camera for a named view. See [`CAMERA_PRESETS`].
+    pub fn preset(name: &str) -> Option<Camera> {
+        let (azimuth, elevation) = match name {
+            // Isometric, looking down the diagonal of a cube
+            "iso" => (45.0, 35.264),
+            "front" => (0.0, 0.0),
+            "back" => (180.0, 0.0),
+            "left" => (-90.0, 0.0),
+            "right" => (90.0, 0.0),
+            "top" => (0.0, 90.0),
+            "bottom" => (0.0, -90.0),
+            _ => return None,
+        };
+        Some(Camera {
+            azimuth,
+            elevation,
+            ..Default::default()
+        })
+    }
+}
+
+/// Grid drawn on the ground under the model, to show how big it is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Grid {
+    // Distance between lines, in model units (usually mm)
+    pub spacing: f32,
+    // Width and depth of the printer bed, centered under the model.
+    // Without a bed the grid only covers the area around the model.
+    pub bed: Option<[f32; 2]>,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            spacing: 10.0,
+            bed: None,
+        }
+    }
+}
+
+/// Animation with the camera going once around the model.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Turntable {
+    pub frames: u32,
+    // How long each frame is shown, in milliseconds
+    pub frame_delay: u16,
+}
+
+impl Default for Turntable {
+    fn default() -> Self {
+        Turntable {
+            frames: 36,
+            frame_delay: 100,
+        }
+    }
+}
+
+/// Several views of the model side by side in one image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sheet {
+    // Label and camera of each cell, filled in row by row
+    pub views: Vec<(String, Camera)>,
+    pub columns: u32,
+    // Write the label of each view in the corner of its cell
+    pub labels: bool,
+}
+
+impl Sheet {
+    /// Sheet with the named views of [`Camera::preset`]. Everything but the direction of the
+    /// cameras comes from `camera`. None if a name is not one of the [`CAMERA_PRESETS`].
+    pub fn from_presets(names: &[&str], camera: &Camera, columns: u32) -> Option<Sheet> {
+        let views = names
+            .iter()
+            .map(|name| {
+                let preset = Camera::preset(name)?;
+                let camera = Camera {
+                    azimuth: preset.azimuth,
+                    elevation: preset.elevation,
+                    ..*camera
+                };
+                Some((name.to_string(), camera))
+            })
+            .collect::<Option<_>>()?;
+        Some(Sheet {
+            views,
+            columns,
+            labels: false,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub enum AAMethod {
     None,
     FXAA,
 }
 
+#[derive(Clone)]
+pub enum Backend {
+    // Try OpenGL first, then fall back to the software rasterizer
+    Auto,
+    OpenGL,
+    Software,
+}
+
+#[derive(Clone)]
+pub struct BatchConfig {
+    // Model files, directories or glob patterns
+    pub inputs: Vec<String>,
+    pub output_dir: String,
+    // Output file name, relative to output_dir. See batch::expand_template.
+    pub template: String,
+    pub recursive: bool,
+    // Render even if the output is newer than the model
+    pub force: bool,
+}
+
+/// Sizes of the freedesktop thumbnail cache, each of which has its own directory.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheSize {
+    Normal,
+    Large,
+    XLarge,
+    XXLarge,
+}
+
+pub const CACHE_SIZES: &[&str] = &["normal", "large", "x-large", "xx-large"];
+
+impl CacheSize {
+    /// Size for one of the [`CACHE_SIZES`].
+    pub fn from_name(name: &str) -> Option<CacheSize> {
+        match name {
+            "normal" => Some(CacheSize::Normal),
+            "large" => Some(CacheSize::Large),
+            "x-large" => Some(CacheSize::XLarge),
+            "xx-large" => Some(CacheSize::XXLarge),
+            _ => None,
+        }
+    }
+
+    /// Name of the directory the thumbnails go in.
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheSize::Normal => "normal",
+            CacheSize::Large => "large",
+            CacheSize::XLarge => "x-large",
+            CacheSize::XXLarge => "xx-large",
+        }
+    }
+
+    /// Width and height of the thumbnails.
+    pub fn pixels(self) -> u32 {
+        match self {
+            CacheSize::Normal => 128,
+            CacheSize::Large => 256,
+            CacheSize::XLarge => 512,
+            CacheSize::XXLarge => 1024,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheConfig {
+    // Model files, directories or glob patterns
+    pub inputs: Vec<String>,
+    // Usually $XDG_CACHE_HOME/thumbnails
+    pub dir: PathBuf,
+    pub sizes: Vec<CacheSize>,
+    pub recursive: bool,
+    // Render even if the cache has a current thumbnail or failure for the model
+    pub force: bool,
+}
+
+// Well-known name of the D-Bus service, its object path is derived from it
+pub const DBUS_NAME: &str = "com.github.unlimitedbacon.StlThumb";
+
+#[derive(Clone)]
+pub struct DbusConfig {
+    // Usually $XDG_CACHE_HOME/thumbnails
+    pub dir: PathBuf,
+    pub name: String,
+    // Bus to connect to, the session bus if None
+    pub address: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ServeConfig {
+    // Address and port to listen on
+    pub address: String,
+    // Directory that models can be rendered from by path. Only uploads are accepted if None.
+    pub root: Option<PathBuf>,
+    // Number of requests rendered at the same time
+    pub workers: usize,
+    // Largest model upload in bytes
+    pub max_upload: u64,
+    // Largest width and height that can be requested
+    pub max_size: u32,
+}
+
+#[derive(Clone)]
+pub enum Mode {
+    // Render MODEL_FILE to IMG_FILE
+    Single,
+    Batch(BatchConfig),
+    // Render thumbnails into the cache of file managers
+    Cache(CacheConfig),
+    // Render thumbnails into the cache when Tumbler asks for them over D-Bus
+    Dbus(DbusConfig),
+    // Render thumbnails for HTTP requests
+    Serve(ServeConfig),
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub model_filename: String,
+    // Format of the model file, detected from its content if None
+    pub input_format: Option<ModelFormat>,
     pub img_filename: String,
     pub format: ImageFormat,
     pub width: u32,
@@ -27,13 +338,28 @@ pub struct Config {
     pub material: Material,
     pub background: (f32, f32, f32, f32),
     pub aamethod: AAMethod,
+    pub camera: Camera,
+    // Cast a shadow onto the ground below the model
+    pub shadow: bool,
+    pub grid: Option<Grid>,
     pub recalc_normals: bool,
+    // Crease angle in degrees for smooth shading. None shades each face flat.
+    pub smooth: Option<f32>,
+    // Larger models are simplified to this many triangles before rendering
+    pub max_triangles: Option<usize>,
+    // Render an animation instead of a still image
+    pub turntable: Option<Turntable>,
+    // Render several views into one image
+    pub sheet: Option<Sheet>,
+    pub backend: Backend,
+    pub mode: Mode,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             model_filename: "".to_string(),
+            input_format: None,
             img_filename: "".to_string(),
             format: ImageFormat::Png,
             width: 1024,
@@ -47,7 +373,52 @@ impl Default for Config {
             },
             background: (0.0, 0.0, 0.0, 0.0),
             aamethod: AAMethod::FXAA,
+            camera: Camera::default(),
+            shadow: false,
+            grid: None,
             recalc_normals: false,
+            smooth: None,
+            max_triangles: None,
+            turntable: None,
+            sheet: None,
+            backend: Backend::Auto,
+            mode: Mode::Single,
+        }
+    }
+}
+
+/// Settings that affect how a single image is drawn.
+///
+/// These can change between renders with the same [`Renderer`](crate::Renderer).
+#[derive(Clone)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub material: Material,
+    pub background: (f32, f32, f32, f32),
+    pub aamethod: AAMethod,
+    pub camera: Camera,
+    pub shadow: bool,
+    pub grid: Option<Grid>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions::from(&Config::default())
+    }
+}
+
+impl From<&Config> for RenderOptions {
+    fn from(config: &Config) -> Self {
+        RenderOptions {
+            width: config.width,
+            height: config.height,
+            material: config.material,
+            background: config.background,
+            aamethod: config.aamethod.clone(),
+            camera: config.camera,
+            shadow: config.shadow,
+            grid: config.grid,
         }
     }
 }
@@ -58,9 +429,10 @@ impl Config {
         let mut matches = clap::Command::new(env!("CARGO_PKG_NAME"))
             .version(env!("CARGO_PKG_VERSION"))
             .author(env!("CARGO_PKG_AUTHORS"))
+            .subcommand_negates_reqs(true)
             .arg(
                 clap::Arg::new("MODEL_FILE")
-                    .help("STL file. Use - to read from stdin instead of a file.")
+                    .help("Model file or file:// URI. Use - to read from stdin instead of a file.")
                     .required(true)
                     .index(1),
             )
@@ -70,15 +442,24 @@ impl Config {
                     .required(true)
                     .index(2),
             )
+            .arg(
+                clap::Arg::new("input_format")
+                    .global(true)
+                    .help("The format of the model file. If not specified it will be detected from the file contents, or the file extension if that fails.")
+                    .long("input-format")
+                    .value_parser(["stl", "obj", "3mf", "ply", "gltf", "glb"]),
+            )
             .arg(
                 clap::Arg::new("format")
-                    .help("The format of the image file. If not specified it will be determined from the file extension, or default to PNG if there is no extension. Supported formats: PNG, JPEG, GIF, ICO, BMP")
+                    .global(true)
+                    .help("The format of the image file. If not specified it will be determined from the file extension, or default to PNG if there is no extension. Supported formats: PNG, JPEG, GIF, ICO, BMP, WebP")
                     .short('f')
                     .long("format")
                     .action(clap::ArgAction::Set)
             )
             .arg(
                 clap::Arg::new("size")
+                    .global(true)
                     .help("Size of thumbnail (square)")
                     .short('s')
                     .long("size")
@@ -93,12 +474,14 @@ impl Config {
             )
             .arg(
                 clap::Arg::new("verbosity")
+                    .global(true)
                     .short('v')
                     .action(clap::ArgAction::Count)
                     .help("Increase message verbosity")
             )
             .arg(
                 clap::Arg::new("material")
+                    .global(true)
                     .help("Colors for rendering the mesh using the Phong reflection model. Requires 3 colors as rgb hex values: ambient, diffuse, and specular. Defaults to blue.")
                     .short('m')
                     .long("material")
@@ -106,6 +489,7 @@ impl Config {
             )
             .arg(
                 clap::Arg::new("background")
+                    .global(true)
                     .help("The background color with transparency (rgba). Default is ffffff00.")
                     .short('b')
                     .long("background")
@@ -114,37 +498,404 @@ impl Config {
             )
             .arg(
                 clap::Arg::new("aamethod")
+                    .global(true)
                     .help("Anti-aliasing method. Default is FXAA, which is fast but may introduce artifacts.")
                     .short('a')
                     .long("antialiasing")
                     .value_parser(["none", "fxaa"]),
             )
+            .arg(
+                clap::Arg::new("view")
+                    .global(true)
+                    .help("Named camera view. The other camera options change individual settings of the view.")
+                    .long("view")
+                    .value_parser(CAMERA_PRESETS.to_vec()),
+            )
+            .arg(
+                clap::Arg::new("azimuth")
+                    .global(true)
+                    .help("Angle in degrees the camera is moved around the model, starting from the front")
+                    .long("azimuth")
+                    .allow_negative_numbers(true)
+                    .value_parser(clap::value_parser!(f32)),
+            )
+            .arg(
+                clap::Arg::new("elevation")
+                    .global(true)
+                    .help("Angle in degrees the camera looks down on the model")
+                    .long("elevation")
+                    .allow_negative_numbers(true)
+                    .value_parser(clap::value_parser!(f32)),
+            )
+            .arg(
+                clap::Arg::new("roll")
+                    .global(true)
+                    .help("Angle in degrees the image is rotated around the viewing direction")
+                    .long("roll")
+                    .allow_negative_numbers(true)
+                    .value_parser(clap::value_parser!(f32)),
+            )
+            .arg(
+                clap::Arg::new("distance")
+                    .global(true)
+                    .help("Distance of the camera from the model, relative to its size. Default is as close as possible while still showing the whole model.")
+                    .long("distance")
+                    .value_parser(parse_distance),
+            )
+            .arg(
+                clap::Arg::new("fov")
+                    .global(true)
+                    .help("Vertical field of view in degrees. Default is 30.")
+                    .long("fov")
+                    .value_parser(parse_fov),
+            )
+            .arg(
+                clap::Arg::new("up")
+                    .global(true)
+                    .help("Axis of the model that points up. Default is z.")
+                    .long("up")
+                    .value_parser(["z", "y"]),
+            )
+            .arg(
+                clap::Arg::new("padding")
+                    .global(true)
+                    .help("Empty space around the model as a percentage of the image size. Default is 5.")
+                    .long("padding")
+                    .value_parser(parse_padding),
+            )
+            .arg(
+                clap::Arg::new("projection")
+                    .global(true)
+                    .help("Camera projection. Default is perspective. Orthographic keeps parallel edges parallel.")
+                    .long("projection")
+                    .value_parser(["perspective", "orthographic"]),
+            )
+            .arg(
+                clap::Arg::new("shadow")
+                    .global(true)
+                    .help("Cast a shadow onto the ground below the model. Only visible when the camera looks down on the model.")
+                    .long("shadow")
+                    .action(clap::ArgAction::SetTrue)
+            )
+            .arg(
+                clap::Arg::new("grid")
+                    .global(true)
+                    .help("Draw a grid on the ground under the model, with lines this many model units (usually mm) apart. Default is 10 when --bed is used.")
+                    .long("grid")
+                    .value_parser(parse_spacing),
+            )
+            .arg(
+                clap::Arg::new("bed")
+                    .global(true)
+                    .help("Size of the printer bed to draw under the model, as WIDTHxDEPTH in model units. Implies --grid.")
+                    .long("bed")
+                    .value_name("WIDTHxDEPTH")
+                    .value_parser(parse_bed),
+            )
             .arg(
                 clap::Arg::new("recalc_normals")
+                    .global(true)
                     .help("Force recalculation of face normals. Use when dealing with malformed STL files.")
                     .long("recalc-normals")
             )
+            .arg(
+                clap::Arg::new("smooth")
+                    .global(true)
+                    .help("Smooth shading. Edges where faces meet at more than the crease angle in degrees stay sharp. Default angle is 30.")
+                    .long("smooth")
+                    .value_name("ANGLE")
+                    .num_args(0..=1)
+                    .default_missing_value("30")
+                    .value_parser(clap::value_parser!(f32)),
+            )
+            .arg(
+                clap::Arg::new("max_triangles")
+                    .global(true)
+                    .help("Simplify models with more triangles than this before rendering them")
+                    .long("max-triangles")
+                    .value_parser(clap::value_parser!(u64).range(1..)),
+            )
+            .arg(
+                clap::Arg::new("turntable")
+                    .global(true)
+                    .help("Render an animation of the model turning around once, with this many frames. Written as APNG, GIF or animated WebP depending on the image format. Default is 36 frames.")
+                    .long("turntable")
+                    .value_name("FRAMES")
+                    .num_args(0..=1)
+                    .default_missing_value("36")
+                    .value_parser(clap::value_parser!(u32).range(1..)),
+            )
+            .arg(
+                clap::Arg::new("frame_delay")
+                    .global(true)
+                    .help("Time each frame of a --turntable animation is shown, in milliseconds. Implies --turntable. Default is 100.")
+                    .long("frame-delay")
+                    .value_name("MS")
+                    .value_parser(clap::value_parser!(u16).range(1..)),
+            )
+            .arg(
+                clap::Arg::new("sheet")
+                    .global(true)
+                    .help("Render a contact sheet with several named views of the model in one image, such as front,right,top,iso. The image size is the size of the whole sheet.")
+                    .long("sheet")
+                    .value_name("VIEWS")
+                    .value_delimiter(',')
+                    .value_parser(CAMERA_PRESETS.to_vec())
+                    .conflicts_with("turntable"),
+            )
+            .arg(
+                clap::Arg::new("columns")
+                    .global(true)
+                    .help("Number of columns of the contact sheet. Default is enough for a square grid.")
+                    .long("columns")
+                    .requires("sheet")
+                    .value_parser(clap::value_parser!(u32).range(1..)),
+            )
+            .arg(
+                clap::Arg::new("labels")
+                    .global(true)
+                    .help("Write the name of each view on the contact sheet")
+                    .long("labels")
+                    .requires("sheet")
+                    .action(clap::ArgAction::SetTrue)
+            )
+            .arg(
+                clap::Arg::new("backend")
+                    .global(true)
+                    .help("Rendering backend. Default is auto, which uses OpenGL if a context can be created and falls back to the software rasterizer otherwise.")
+                    .long("backend")
+                    .value_parser(["auto", "opengl", "software"]),
+            )
+            .subcommand(
+                clap::Command::new("batch")
+                    .about("Render thumbnails for many model files in a single process")
+                    .arg(
+                        clap::Arg::new("INPUT")
+                            .help("Model files, directories or glob patterns such as \"models/*.stl\"")
+                            .required(true)
+                            .num_args(1..)
+                    )
+                    .arg(
+                        clap::Arg::new("output_dir")
+                            .help("Directory the thumbnails are written to")
+                            .short('o')
+                            .long("output-dir")
+                            .required(true)
+                    )
+                    .arg(
+                        clap::Arg::new("template")
+                            .help("File name for each thumbnail. {stem}, {name} and {ext} are replaced with parts of the model file name, {dir} with its directory relative to the input it was found in.")
+                            .short('t')
+                            .long("template")
+                            .default_value("{stem}.png")
+                    )
+                    .arg(
+                        clap::Arg::new("recursive")
+                            .help("Search directories recursively")
+                            .short('r')
+                            .long("recursive")
+                            .action(clap::ArgAction::SetTrue)
+                    )
+                    .arg(
+                        clap::Arg::new("force")
+                            .help("Render thumbnails even if they are newer than the model file")
+                            .long("force")
+                            .action(clap::ArgAction::SetTrue)
+                    )
+            )
+            .subcommand(
+                clap::Command::new("cache")
+                    .about("Render thumbnails into the thumbnail cache of file managers, $XDG_CACHE_HOME/thumbnails")
+                    .arg(
+                        clap::Arg::new("INPUT")
+                            .help("Model files, directories or glob patterns such as \"models/*.stl\"")
+                            .required(true)
+                            .num_args(1..)
+                    )
+                    .arg(
+                        clap::Arg::new("sizes")
+                            .help("Thumbnail sizes to render, separated by commas")
+                            .long("sizes")
+                            .value_delimiter(',')
+                            .value_parser(CACHE_SIZES.to_vec())
+                            .default_value("normal,large")
+                    )
+                    .arg(
+                        clap::Arg::new("recursive")
+                            .help("Search directories recursively")
+                            .short('r')
+                            .long("recursive")
+                            .action(clap::ArgAction::SetTrue)
+                    )
+                    .arg(
+                        clap::Arg::new("force")
+                            .help("Render thumbnails even if the cache has current ones, or failed for the model before")
+                            .long("force")
+                            .action(clap::ArgAction::SetTrue)
+                    )
+            )
+            .subcommand(
+                clap::Command::new("dbus")
+                    .about("Run as a specialized thumbnailer service for Tumbler, rendering thumbnails into the thumbnail cache when they are requested over D-Bus")
+                    .arg(
+                        clap::Arg::new("name")
+                            .help("Name to own on the bus. The object path is derived from it.")
+                            .long("name")
+                            .default_value(DBUS_NAME)
+                    )
+                    .arg(
+                        clap::Arg::new("address")
+                            .help("Address of the bus to connect to. Default is the session bus.")
+                            .long("address")
+                    )
+            )
+            .subcommand(
+                clap::Command::new("serve")
+                    .about("Run an HTTP server that renders uploaded models, or models in a directory, on request")
+                    .arg(
+                        clap::Arg::new("listen")
+                            .help("Address and port to listen on")
+                            .short('l')
+                            .long("listen")
+                            .default_value("127.0.0.1:8080")
+                    )
+                    .arg(
+                        clap::Arg::new("root")
+                            .help("Directory that models can be rendered from by path. Without it only uploads are accepted.")
+                            .long("root")
+                    )
+                    .arg(
+                        clap::Arg::new("workers")
+                            .help("Number of requests to render at the same time. Uploads are read without a timeout, so slow clients keep a worker busy.")
+                            .short('w')
+                            .long("workers")
+                            .value_parser(clap::value_parser!(u32).range(1..))
+                            .default_value("2")
+                    )
+                    .arg(
+                        clap::Arg::new("max_upload")
+                            .help("Largest model upload in megabytes")
+                            .long("max-upload")
+                            .value_parser(clap::value_parser!(u64).range(1..))
+                            .default_value("64")
+                    )
+                    .arg(
+                        clap::Arg::new("max_size")
+                            .help("Largest width and height in pixels that can be requested. The memory each worker needs grows with its square.")
+                            .long("max-size")
+                            .value_parser(clap::value_parser!(u32).range(1..=4096))
+                            .default_value("1024")
+                    )
+            )
             .get_matches();
 
         let mut c = Config {
             ..Default::default()
         };
 
-        c.model_filename = matches
-            .remove_one::<String>("MODEL_FILE")
-            .expect("MODEL_FILE not provided");
-        c.img_filename = matches
-            .remove_one::<String>("IMG_FILE")
-            .expect("IMG_FILE not provided");
+        // Options shared by all modes are global, so they can be read from the subcommand matches
+        let matches =
+            match matches.remove_subcommand() {
+                Some((name, mut sub_matches)) => {
+                    match name.as_str() {
+                        "batch" => {
+                            c.mode = Mode::Batch(BatchConfig {
+                                inputs: sub_matches
+                                    .remove_many::<String>("INPUT")
+                                    .expect("INPUT not provided")
+                                    .collect(),
+                                output_dir: sub_matches
+                                    .remove_one::<String>("output_dir")
+                                    .expect("Output directory not provided"),
+                                template: sub_matches
+                                    .remove_one::<String>("template")
+                                    .expect("Template not provided"),
+                                recursive: sub_matches.get_flag("recursive"),
+                                force: sub_matches.get_flag("force"),
+                            })
+                        }
+                        "cache" => c.mode = Mode::Cache(CacheConfig {
+                            inputs: sub_matches
+                                .remove_many::<String>("INPUT")
+                                .expect("INPUT not provided")
+                                .collect(),
+                            dir: thumbnail_cache_dir().expect(
+                                "Unable to find the cache directory. Set XDG_CACHE_HOME or HOME.",
+                            ),
+                            sizes: sub_matches
+                                .get_many::<String>("sizes")
+                                .expect("Sizes not provided")
+                                .filter_map(|name| CacheSize::from_name(name))
+                                .collect(),
+                            recursive: sub_matches.get_flag("recursive"),
+                            force: sub_matches.get_flag("force"),
+                        }),
+                        "dbus" => c.mode = Mode::Dbus(DbusConfig {
+                            dir: thumbnail_cache_dir().expect(
+                                "Unable to find the cache directory. Set XDG_CACHE_HOME or HOME.",
+                            ),
+                            name: sub_matches
+                                .remove_one::<String>("name")
+                                .expect("Name not provided"),
+                            address: sub_matches.remove_one::<String>("address"),
+                        }),
+                        "serve" => {
+                            c.mode = Mode::Serve(ServeConfig {
+                                address: sub_matches
+                                    .remove_one::<String>("listen")
+                                    .expect("Address not provided"),
+                                root: sub_matches.remove_one::<String>("root").map(PathBuf::from),
+                                workers: *sub_matches
+                                    .get_one::<u32>("workers")
+                                    .expect("Workers not provided")
+                                    as usize,
+                                max_upload: sub_matches
+                                    .get_one::<u64>("max_upload")
+                                    .expect("Upload limit not provided")
+                                    * 1024
+                                    * 1024,
+                                max_size: *sub_matches
+                                    .get_one::<u32>("max_size")
+                                    .expect("Size limit not provided"),
+                            })
+                        }
+                        _ => unreachable!(),
+                    }
+                    sub_matches
+                }
+                None => {
+                    c.model_filename = matches
+                        .remove_one::<String>("MODEL_FILE")
+                        .expect("MODEL_FILE not provided");
+                    c.img_filename = matches
+                        .remove_one::<String>("IMG_FILE")
+                        .expect("IMG_FILE not provided");
+                    c.visible = matches.contains_id("visible");
+                    matches
+                }
+            };
+
+        let img_filename = match c.mode {
+            Mode::Batch(ref batch) => batch.template.as_str(),
+            Mode::Single => c.img_filename.as_str(),
+            // Always PNG
+            Mode::Cache(_) | Mode::Dbus(_) => "",
+            // Chosen per request
+            Mode::Serve(_) => "",
+        };
         match matches.get_one::<String>("format") {
             Some(x) => c.format = match_format(x),
             None => {
-                if let Some(ext) = Path::new(&c.img_filename).extension() {
+                if let Some(ext) = Path::new(img_filename).extension() {
                     c.format = match_format(ext.to_str().unwrap());
                 }
             }
         };
 
+        if let Some(x) = matches.get_one::<String>("input_format") {
+            c.input_format = ModelFormat::from_extension(x);
+        }
+
         if let Some(x) = matches.get_one::<String>("size") {
             c.width = x.parse::<u32>().expect("Invalid size");
         }
@@ -153,7 +904,6 @@ impl Config {
             c.height = x.parse::<u32>().expect("Invalid size");
         }
 
-        c.visible = matches.contains_id("visible");
         c.verbosity = matches.get_count("verbosity") as usize;
         if let Some(materials) = matches.get_many::<String>("material") {
             let mut iter = materials.map(|m| html_to_rgb(m));
@@ -173,12 +923,155 @@ impl Config {
                 _ => unreachable!(),
             }
         }
+        if let Some(x) = matches.get_one::<String>("view") {
+            c.camera = Camera::preset(x).expect("Unknown view");
+        }
+        if let Some(x) = matches.get_one::<f32>("azimuth") {
+            c.camera.azimuth = *x;
+        }
+        if let Some(x) = matches.get_one::<f32>("elevation") {
+            c.camera.elevation = *x;
+        }
+        if let Some(x) = matches.get_one::<f32>("roll") {
+            c.camera.roll = *x;
+        }
+        if let Some(x) = matches.get_one::<f32>("distance") {
+            c.camera.distance = *x;
+        }
+        if let Some(x) = matches.get_one::<f32>("fov") {
+            c.camera.fov = *x;
+        }
+        if let Some(x) = matches.get_one::<String>("up") {
+            match x.as_str() {
+                "z" => c.camera.up = UpAxis::Z,
+                "y" => c.camera.up = UpAxis::Y,
+                _ => unreachable!(),
+            }
+        }
+        if let Some(x) = matches.get_one::<f32>("padding") {
+            c.camera.padding = *x;
+        }
+        if let Some(x) = matches.get_one::<String>("projection") {
+            match x.as_str() {
+                "perspective" => c.camera.projection = Projection::Perspective,
+                "orthographic" => c.camera.projection = Projection::Orthographic,
+                _ => unreachable!(),
+            }
+        }
+        c.shadow = matches.get_flag("shadow");
+        if let Some(x) = matches.get_one::<f32>("grid") {
+            c.grid = Some(Grid {
+                spacing: *x,
+                ..Default::default()
+            });
+        }
+        if let Some(x) = matches.get_one::<[f32; 2]>("bed") {
+            c.grid = Some(Grid {
+                bed: Some(*x),
+                ..c.grid.unwrap_or_default()
+            });
+        }
         c.recalc_normals = matches.contains_id("recalc_normals");
+        c.smooth = matches.get_one::<f32>("smooth").copied();
+        c.max_triangles = matches
+            .get_one::<u64>("max_triangles")
+            .map(|&max| max as usize);
+        if let Some(x) = matches.get_one::<u32>("turntable") {
+            c.turntable = Some(Turntable {
+                frames: *x,
+                ..Default::default()
+            });
+        }
+        if let Some(x) = matches.get_one::<u16>("frame_delay") {
+            c.turntable = Some(Turntable {
+                frame_delay: *x,
+                ..c.turntable.unwrap_or_default()
+            });
+        }
+        // After the camera options, which apply to every view of the sheet
+        if let Some(names) = matches.get_many::<String>("sheet") {
+            let names: Vec<&str> = names.map(String::as_str).collect();
+            let columns = match matches.get_one::<u32>("columns") {
+                Some(x) => *x,
+                None => (names.len() as f32).sqrt().ceil() as u32,
+            };
+            let mut sheet = Sheet::from_presets(&names, &c.camera, columns).expect("Unknown view");
+            sheet.labels = matches.get_flag("labels");
+            c.sheet = Some(sheet);
+        }
+        if let Some(x) = matches.get_one::<String>("backend") {
+            match x.as_str() {
+                "auto" => c.backend = Backend::Auto,
+                "opengl" => c.backend = Backend::OpenGL,
+                "software" => c.backend = Backend::Software,
+                _ => unreachable!(),
+            }
+        }
 
         c
     }
 }
 
+fn valid_distance(x: f32) -> bool {
+    x > 0.0 && x.is_finite()
+}
+
+// Also false for NaN
+fn valid_fov(x: f32) -> bool {
+    x > 0.0 && x < 180.0
+}
+
+fn valid_padding(x: f32) -> bool {
+    (0.0..50.0).contains(&x)
+}
+
+fn parse_distance(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if valid_distance(x) => Ok(x),
+        _ => Err("Expected a distance greater than 0".to_string()),
+    }
+}
+
+fn parse_fov(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if valid_fov(x) => Ok(x),
+        _ => Err("Expected an angle between 0 and 180".to_string()),
+    }
+}
+
+fn parse_padding(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if valid_padding(x) => Ok(x),
+        _ => Err("Expected a percentage of at least 0 and below 50".to_string()),
+    }
+}
+
+fn parse_spacing(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if valid_distance(x) => Ok(x),
+        _ => Err("Expected a distance greater than 0".to_string()),
+    }
+}
+
+fn parse_bed(s: &str) -> Result<[f32; 2], String> {
+    let size = s
+        .split_once(['x', 'X'])
+        .and_then(|(w, d)| Some([w.trim().parse::<f32>().ok()?, d.trim().parse::<f32>().ok()?]));
+    match size {
+        Some([w, d]) if valid_distance(w) && valid_distance(d) => Ok([w, d]),
+        _ => Err("Expected a size such as 220x220".to_string()),
+    }
+}
+
+// Thumbnail cache of the freedesktop thumbnail spec. XDG_CACHE_HOME is ignored unless it's absolute.
+fn thumbnail_cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("thumbnails"))
+}
+
 fn match_format(ext: &str) -> ImageFormat {
     match ext.to_lowercase().as_str() {
         "png" => ImageFormat::Png,
@@ -186,6 +1079,7 @@ fn match_format(ext: &str) -> ImageFormat {
         "gif" => ImageFormat::Gif,
         "ico" => ImageFormat::Ico,
         "bmp" => ImageFormat::Bmp,
+        "webp" => ImageFormat::WebP,
         _ => {
             warn!("Unsupported image format. Using PNG instead.");
             ImageFormat::Png
@@ -193,6 +1087,18 @@ fn match_format(ext: &str) -> ImageFormat {
     }
 }
 
+// Color from pairs of hex digits such as ff8000, which must be exactly N channels
+pub(crate) fn hex_color<const N: usize>(color: &str) -> Option<[f32; N]> {
+    if color.len() != N * 2 {
+        return None;
+    }
+    let mut channels = [0.0; N];
+    for (i, channel) in channels.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(color.get(i * 2..i * 2 + 2)?, 16).ok()? as f32 / 255.0;
+    }
+    Some(channels)
+}
+
 fn html_to_rgb(color: &str) -> [f32; 3] {
     let r: f32 = u8::from_str_radix(&color[0..2], 16).expect("Invalid color") as f32 / 255.0;
     let g: f32 = u8::from_str_radix(&color[2..4], 16).expect("Invalid color") as f32 / 255.0;
@@ -207,3 +1113,58 @@ fn html_to_rgba(color: &str) -> (f32, f32, f32, f32) {
     let a: f32 = u8::from_str_radix(&color[6..8], 16).expect("Invalid color") as f32 / 255.0;
     (r, g, b, a)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_values() {
+        assert_eq!(Ok(45.0), parse_fov("45"));
+        for fov in ["0", "180", "-10", "inf", "NaN"] {
+            assert!(parse_fov(fov).is_err(), "{}", fov);
+        }
+        assert_eq!(Ok(0.0), parse_padding("0"));
+        for padding in ["50", "-1", "inf", "NaN"] {
+            assert!(parse_padding(padding).is_err(), "{}", padding);
+        }
+        assert_eq!(Ok(3.5), parse_distance("3.5"));
+        for distance in ["0", "-2", "inf", "NaN"] {
+            assert!(parse_distance(distance).is_err(), "{}", distance);
+        }
+
+        assert!(Camera::default().check().is_ok());
+        for camera in [
+            Camera {
+                fov: 0.0,
+                ..Default::default()
+            },
+            Camera {
+                distance: -1.0,
+                ..Default::default()
+            },
+            Camera {
+                padding: f32::NAN,
+                ..Default::default()
+            },
+            Camera {
+                roll: f32::INFINITY,
+                ..Default::default()
+            },
+        ] {
+            assert!(camera.check().is_err(), "{:?}", camera);
+        }
+    }
+
+    #[test]
+    fn grid_values() {
+        assert_eq!(Ok(2.5), parse_spacing("2.5"));
+        for spacing in ["0", "-1", "inf", "NaN"] {
+            assert!(parse_spacing(spacing).is_err(), "{}", spacing);
+        }
+        assert_eq!(Ok([220.0, 250.0]), parse_bed("220x250"));
+        for bed in ["220", "0x220", "infxinf", "220xinf", "NaNx220"] {
+            assert!(parse_bed(bed).is_err(), "{}", bed);
+        }
+    }
+}
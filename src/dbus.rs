@@ -0,0 +1,318 @@
+// D-Bus Thumbnailer
+// =================
+//
+// Tumbler, the thumbnail service of Xfce and others, can hand thumbnails over to specialized
+// thumbnailers on D-Bus instead of running a program for each file. Requests are queued by the
+// bus connection's own thread and rendered one at a time on the thread that runs the service,
+// which owns the Renderer and so keeps one GL context for all of them.
+// https://wiki.gnome.org/DraftSpecs/ThumbnailerSpec
+
+use crate::batch::Outcome;
+use crate::cache::cache_model;
+use crate::config::{CacheConfig, CacheSize, Config, DbusConfig};
+use crate::error::Error;
+use crate::{uri, Renderer};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use zbus::blocking::connection;
+
+const INTERFACE: &str = "org.freedesktop.thumbnails.SpecializedThumbnailer1";
+
+// Error codes of Tumbler's TumblerErrorEnum
+const ERROR_FAILED: i32 = 0;
+const ERROR_INVALID_FORMAT: i32 = 1;
+const ERROR_SAVE_FAILED: i32 = 4;
+const ERROR_UNSUPPORTED_FLAVOR: i32 = 7;
+
+struct Request {
+    handle: u32,
+    uri: String,
+    flavor: String,
+}
+
+#[derive(Default)]
+struct Pending {
+    last_handle: u32,
+    requests: VecDeque<Request>,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Mutex<Pending>,
+    added: Condvar,
+}
+
+impl Queue {
+    // Waits for the next request
+    fn next(&self) -> Request {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if let Some(request) = pending.requests.pop_front() {
+                return request;
+            }
+            pending = self.added.wait(pending).unwrap();
+        }
+    }
+}
+
+struct Thumbnailer {
+    queue: Arc<Queue>,
+}
+
+// The Ready, Error and Finished signals are sent by the rendering thread, see run
+#[zbus::interface(name = "org.freedesktop.thumbnails.SpecializedThumbnailer1")]
+impl Thumbnailer {
+    /// Queues a thumbnail of `uri` in the size named by `flavor`. Urgent requests go first.
+    fn queue(&self, uri: String, mime_type: String, flavor: String, urgent: bool) -> u32 {
+        let mut pending = self.queue.pending.lock().unwrap();
+        pending.last_handle = pending.last_handle.wrapping_add(1).max(1);
+        let handle = pending.last_handle;
+        debug!("Queued {} ({}) as {}", uri, mime_type, handle);
+        let request = Request {
+            handle,
+            uri,
+            flavor,
+        };
+        if urgent {
+            pending.requests.push_front(request);
+        } else {
+            pending.requests.push_back(request);
+        }
+        self.queue.added.notify_one();
+        handle
+    }
+
+    /// Drops a request that hasn't been started yet.
+    fn dequeue(&self, handle: u32) {
+        let mut pending = self.queue.pending.lock().unwrap();
+        pending.requests.retain(|request| request.handle != handle);
+    }
+}
+
+// Object path of a bus name, /com/example/Name for com.example.Name
+fn object_path(name: &str) -> String {
+    format!("/{}", name.replace('.', "/"))
+}
+
+/// Serves thumbnail requests on the bus given in `dbus` until an error occurs.
+///
+/// Thumbnails are written into the cache directory of `dbus` with the rendering options of
+/// `config`, the same way the cache mode writes them.
+pub fn run(config: &Config, dbus: &DbusConfig) -> Result<(), Error> {
+    let queue = Arc::new(Queue::default());
+    let path = object_path(&dbus.name);
+    let builder = match dbus.address {
+        Some(ref address) => connection::Builder::address(address.as_str())?,
+        None => connection::Builder::session()?,
+    };
+    let connection = builder
+        .name(dbus.name.as_str())?
+        .serve_at(
+            path.as_str(),
+            Thumbnailer {
+                queue: queue.clone(),
+            },
+        )?
+        .build()?;
+    info!("Serving thumbnails as {} at {}", dbus.name, path);
+
+    let mut renderer = Renderer::new(config)?;
+    loop {
+        let request = queue.next();
+        let path = path.as_str();
+        let uri = request.uri.as_str();
+        match thumbnail(config, dbus, &mut renderer, &request) {
+            Ok(()) => connection.emit_signal(
+                None::<()>,
+                path,
+                INTERFACE,
+                "Ready",
+                &(request.handle, uri),
+            )?,
+            Err((code, message)) => {
+                warn!("Thumbnail of {} failed: {}", uri, message);
+                connection.emit_signal(
+                    None::<()>,
+                    path,
+                    INTERFACE,
+                    "Error",
+                    &(request.handle, uri, code, message.as_str()),
+                )?
+            }
+        }
+        connection.emit_signal(None::<()>, path, INTERFACE, "Finished", &request.handle)?;
+    }
+}
+
+// Renders a request into the cache, or returns the Tumbler error code and message
+fn thumbnail(
+    config: &Config,
+    dbus: &DbusConfig,
+    renderer: &mut Renderer,
+    request: &Request,
+) -> Result<(), (i32, String)> {
+    let size = CacheSize::from_name(&request.flavor).ok_or_else(|| {
+        (
+            ERROR_UNSUPPORTED_FLAVOR,
+            format!("Unsupported flavor {}", request.flavor),
+        )
+    })?;
+    let model = uri::model_path(&request.uri).map_err(|e| (ERROR_FAILED, e.to_string()))?;
+    let cache = CacheConfig {
+        inputs: Vec::new(),
+        dir: dbus.dir.clone(),
+        sizes: vec![size],
+        recursive: false,
+        // Tumbler checks the cache itself before asking
+        force: true,
+    };
+    let mut result = Ok(());
+    cache_model(
+        config,
+        &cache,
+        renderer,
+        &model,
+        &request.uri,
+        &mut |_, outcome| {
+            if let Outcome::Failed(e) = outcome {
+                result = Err((error_code(&e), e.to_string()));
+            }
+        },
+    );
+    result
+}
+
+fn error_code(e: &Error) -> i32 {
+    match e {
+        Error::UnsupportedFormat(_)
+        | Error::UnknownFormat
+        | Error::EmptyModel
+        | Error::Parse { .. } => ERROR_INVALID_FORMAT,
+        Error::Encode(_) => ERROR_SAVE_FAILED,
+        _ => ERROR_FAILED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::thumbnail_name;
+    use crate::config::Backend;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+    use std::{fs, thread, time};
+    use zbus::blocking::MessageIterator;
+    use zbus::message::Type;
+    use zbus::MatchRule;
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            "/com/github/unlimitedbacon/StlThumb",
+            object_path(crate::config::DBUS_NAME)
+        );
+    }
+
+    // Serves thumbnails on a private bus, if dbus-daemon is installed
+    #[test]
+    fn private_bus() {
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                println!("Skipping, unable to start dbus-daemon: {}", e);
+                return;
+            }
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let dir = std::env::temp_dir().join(format!("stl-thumb-dbus-{}", std::process::id()));
+        let name = "com.github.unlimitedbacon.StlThumbTest";
+        let config = Config {
+            backend: Backend::Software,
+            ..Default::default()
+        };
+        let dbus = DbusConfig {
+            dir: dir.clone(),
+            name: name.to_string(),
+            address: Some(address.clone()),
+        };
+        // Runs until the bus goes away
+        thread::spawn(move || run(&config, &dbus));
+
+        let client = connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .unwrap();
+        let bus = zbus::blocking::fdo::DBusProxy::new(&client).unwrap();
+        for _ in 0..100 {
+            if bus.name_has_owner(name.try_into().unwrap()).unwrap() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .interface(INTERFACE)
+            .unwrap()
+            .build();
+        let mut signals = MessageIterator::for_match_rule(rule, &client, None).unwrap();
+
+        let uri = uri::file_uri(&fs::canonicalize("test_data/cube.stl").unwrap()).unwrap();
+        let queue = |flavor: &str| -> u32 {
+            client
+                .call_method(
+                    Some(name),
+                    object_path(name).as_str(),
+                    Some(INTERFACE),
+                    "Queue",
+                    &(uri.as_str(), "model/stl", flavor, false),
+                )
+                .unwrap()
+                .body()
+                .deserialize()
+                .unwrap()
+        };
+        let mut received = |handle: u32| -> Vec<String> {
+            let mut members = Vec::new();
+            for msg in signals.by_ref() {
+                let msg = msg.unwrap();
+                let member = msg.header().member().unwrap().to_string();
+                let body = msg.body();
+                let signal_handle = match member.as_str() {
+                    "Ready" => body.deserialize::<(u32, &str)>().unwrap().0,
+                    "Error" => body.deserialize::<(u32, &str, i32, &str)>().unwrap().0,
+                    _ => body.deserialize::<u32>().unwrap(),
+                };
+                if signal_handle != handle {
+                    continue;
+                }
+                members.push(member.clone());
+                if member == "Finished" {
+                    break;
+                }
+            }
+            members
+        };
+
+        let handle = queue("large");
+        assert_eq!(vec!["Ready", "Finished"], received(handle));
+        let thumbnail = dir.join("large").join(thumbnail_name(&uri));
+        assert_eq!(256, image::open(thumbnail).unwrap().width());
+
+        let handle = queue("huge");
+        assert_eq!(vec!["Error", "Finished"], received(handle));
+
+        daemon.kill().unwrap();
+        daemon.wait().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
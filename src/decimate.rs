@@ -0,0 +1,156 @@
+// Decimation
+// ==========
+//
+// Huge scans take a long time to draw and can run out of memory with osmesa, while a
+// thumbnail only has so many pixels to show them. Vertex clustering puts the model in a
+// grid and merges all vertices in each cell into one. Triangles with corners in the same
+// cell disappear. It only takes a few passes over the mesh, no matter how big it is, and
+// keeps the overall shape and silhouette of the model.
+
+use crate::mesh::Mesh;
+use ahash::{AHashMap, AHashSet};
+use cgmath::{EuclideanSpace, Vector3};
+
+// Shrinking the grid more than needed each round saves rounds
+const SHRINK_MARGIN: f32 = 0.95;
+
+pub(crate) struct Clustered {
+    // Average position of the vertices in each cell
+    pub positions: Vec<[f32; 3]>,
+    // Cells of the corners of each remaining triangle, with the vertices they came from
+    pub triangles: Vec<([u32; 3], [usize; 3])>,
+}
+
+// Cell of every vertex, numbered in the order they are found
+fn clusters(mesh: &Mesh, cells: f32) -> (Vec<u32>, usize) {
+    let min = mesh.bounds.min.to_vec();
+    let size = mesh.bounds.max.to_vec() - min;
+    let cell = size.x.max(size.y).max(size.z) / cells;
+    let mut ids: AHashMap<[i32; 3], u32> = AHashMap::new();
+    let cluster = mesh
+        .vertices
+        .iter()
+        .map(|v| {
+            let p = (Vector3::from(v.position) - min) / cell;
+            let key = [p.x, p.y, p.z].map(|x| x.floor() as i32);
+            let next = ids.len() as u32;
+            *ids.entry(key).or_insert(next)
+        })
+        .collect();
+    (cluster, ids.len())
+}
+
+// Triangles that still have three different corners, each one only once
+fn remaining(mesh: &Mesh, cluster: &[u32]) -> Vec<([u32; 3], [usize; 3])> {
+    let mut seen = AHashSet::new();
+    mesh.triangles()
+        .filter_map(|tri| {
+            let cells = tri.map(|v| cluster[v]);
+            let [a, b, c] = cells;
+            if a == b || b == c || c == a {
+                return None;
+            }
+            // Same triangle starting from its smallest corner. The opposite winding is a
+            // different triangle, which thin walls need.
+            let first = (0..3).min_by_key(|&i| cells[i]).unwrap();
+            let key = [0, 1, 2].map(|i| cells[(first + i) % 3]);
+            seen.insert(key).then_some((cells, tri))
+        })
+        .collect()
+}
+
+/// Clusters the vertices of `mesh` until it has at most `max_triangles` triangles, or keeps only
+/// some of its triangles if even one cell per side leaves too many. None if it already has few
+/// enough.
+pub(crate) fn decimate(mesh: &Mesh, max_triangles: usize) -> Option<Clustered> {
+    let count = mesh.indices.len() / 3;
+    let max_triangles = max_triangles.max(1);
+    if count <= max_triangles {
+        return None;
+    }
+
+    // A closed surface on a grid of n cells per side has roughly n^2 triangles
+    let mut cells = (max_triangles as f32).sqrt().max(1.0);
+    let (cluster, cluster_count, triangles) = loop {
+        let (cluster, cluster_count) = clusters(mesh, cells);
+        let triangles = remaining(mesh, &cluster);
+        if triangles.len() <= max_triangles || cells <= 1.0 {
+            break (cluster, cluster_count, triangles);
+        }
+        debug!("{} cells give {} triangles", cells, triangles.len());
+        cells = (cells * (max_triangles as f32 / triangles.len() as f32).sqrt() * SHRINK_MARGIN)
+            .max(1.0);
+    };
+    // Even the coarsest grid can leave more than tiny budgets allow, or nothing at all
+    if triangles.is_empty() || triangles.len() > max_triangles {
+        warn!(
+            "Keeping {} of {} triangles, as merging vertices can't meet the budget",
+            max_triangles, count
+        );
+        return Some(sample(mesh, max_triangles));
+    }
+    info!(
+        "Decimated {} triangles to {} with a grid of {} cells",
+        count,
+        triangles.len(),
+        cells
+    );
+
+    let mut sums = vec![(Vector3::new(0.0, 0.0, 0.0), 0.0f32); cluster_count];
+    for (v, &c) in mesh.vertices.iter().zip(&cluster) {
+        let sum = &mut sums[c as usize];
+        sum.0 += Vector3::from(v.position);
+        sum.1 += 1.0;
+    }
+    Some(Clustered {
+        positions: sums.iter().map(|(p, n)| (p / *n).into()).collect(),
+        triangles,
+    })
+}
+
+// Every so many triangles of the mesh, as they are
+fn sample(mesh: &Mesh, max_triangles: usize) -> Clustered {
+    let step = (mesh.indices.len() / 3).div_ceil(max_triangles);
+    Clustered {
+        positions: mesh.vertices.iter().map(|v| v.position).collect(),
+        triangles: mesh
+            .triangles()
+            .step_by(step)
+            .map(|tri| (tri.map(|v| v as u32), tri))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::Mesh;
+
+    #[test]
+    fn sphere_budget() {
+        let mut mesh = Mesh::load("test_data/sphere.stl", false).unwrap();
+        let before = mesh.bounds.clone();
+        mesh.decimate(200);
+        let count = mesh.triangles().count();
+        assert!(count <= 200 && count > 50, "{}", count);
+        // Still about the same size
+        for i in 0..3 {
+            let size = before.max[i] - before.min[i];
+            assert!((mesh.bounds.min[i] - before.min[i]).abs() < 0.1 * size);
+            assert!((mesh.bounds.max[i] - before.max[i]).abs() < 0.1 * size);
+        }
+    }
+
+    #[test]
+    fn within_budget() {
+        let mut mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        mesh.decimate(12);
+        assert_eq!(12, mesh.triangles().count());
+    }
+
+    #[test]
+    fn tiny_budget() {
+        let mut mesh = Mesh::load("test_data/sphere.stl", false).unwrap();
+        mesh.decimate(1);
+        assert_eq!(1, mesh.triangles().count());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{error, fmt, io};
+
+/// Everything that can go wrong while loading or rendering a model.
+#[derive(Debug)]
+pub enum Error {
+    /// The model is in a format that can't be read. Contains the file extension.
+    UnsupportedFormat(String),
+    /// The format of model data without a file name could not be detected.
+    UnknownFormat,
+    /// The model was given as a URI that can't be read, like one for a remote file.
+    InvalidUri(String),
+    /// The model file was read successfully but contains no triangles.
+    EmptyModel,
+    /// The model file is malformed.
+    Parse {
+        format: &'static str,
+        message: String,
+    },
+    /// No OpenGL context could be created.
+    Context(String),
+    /// The shaders failed to compile.
+    Shader(String),
+    /// OpenGL failed while uploading or drawing the model.
+    Render(String),
+    /// The image could not be encoded.
+    Encode(image::ImageError),
+    /// The D-Bus service could not be started, or lost its connection.
+    Bus(String),
+    /// The model references a file outside of the directory it may be loaded from. Contains
+    /// the reference as written in the model.
+    OutsideRoot(String),
+    Io(io::Error),
+}
+
+impl Error {
+    pub(crate) fn parse<E: fmt::Display>(format: &'static str, err: E) -> Error {
+        Error::Parse {
+            format,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedFormat(ext) => write!(f, "Format not supported: {:?}", ext),
+            Error::UnknownFormat => write!(f, "Unrecognized model data"),
+            Error::InvalidUri(e) => write!(f, "Unsupported URI {}", e),
+            Error::EmptyModel => write!(f, "Model contains no triangles"),
+            Error::Parse { format, message } => write!(f, "Invalid {} file: {}", format, message),
+            Error::Context(e) => write!(f, "Unable to create GL context: {}", e),
+            Error::Shader(e) => write!(f, "Unable to compile shaders: {}", e),
+            Error::Render(e) => write!(f, "Rendering failed: {}", e),
+            Error::Encode(e) => write!(f, "Unable to encode image: {}", e),
+            Error::Bus(e) => write!(f, "D-Bus error: {}", e),
+            Error::OutsideRoot(reference) => write!(
+                f,
+                "Model references {}, which is outside of the allowed directory",
+                reference
+            ),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Encode(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Error {
+        Error::Encode(err)
+    }
+}
+
+// The png crate is used directly for animations, image doesn't write APNG
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Error {
+        Error::Encode(image::ImageError::Encoding(
+            image::error::EncodingError::new(image::ImageFormat::Png.into(), err),
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl From<zbus::Error> for Error {
+    fn from(err: zbus::Error) -> Error {
+        Error::Bus(err.to_string())
+    }
+}
+
+// Context creation
+// ----------------
+
+impl From<glium::glutin::CreationError> for Error {
+    fn from(err: glium::glutin::CreationError) -> Error {
+        Error::Context(err.to_string())
+    }
+}
+
+impl From<glium::backend::glutin::DisplayCreationError> for Error {
+    fn from(err: glium::backend::glutin::DisplayCreationError) -> Error {
+        Error::Context(err.to_string())
+    }
+}
+
+impl From<glium::IncompatibleOpenGl> for Error {
+    fn from(err: glium::IncompatibleOpenGl) -> Error {
+        Error::Context(err.to_string())
+    }
+}
+
+// Rendering
+// ---------
+
+impl From<glium::ProgramCreationError> for Error {
+    fn from(err: glium::ProgramCreationError) -> Error {
+        Error::Shader(err.to_string())
+    }
+}
+
+impl From<glium::program::ProgramChooserCreationError> for Error {
+    fn from(err: glium::program::ProgramChooserCreationError) -> Error {
+        Error::Shader(err.to_string())
+    }
+}
+
+impl From<glium::vertex::BufferCreationError> for Error {
+    fn from(err: glium::vertex::BufferCreationError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+impl From<glium::index::BufferCreationError> for Error {
+    fn from(err: glium::index::BufferCreationError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+impl From<glium::texture::TextureCreationError> for Error {
+    fn from(err: glium::texture::TextureCreationError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+impl From<glium::framebuffer::ValidationError> for Error {
+    fn from(err: glium::framebuffer::ValidationError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+impl From<glium::framebuffer::RenderBufferCreationError> for Error {
+    fn from(err: glium::framebuffer::RenderBufferCreationError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
+
+impl From<glium::DrawError> for Error {
+    fn from(err: glium::DrawError) -> Error {
+        Error::Render(err.to_string())
+    }
+}
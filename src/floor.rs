@@ -0,0 +1,190 @@
+// Build Plate
+// ===========
+//
+// A grid on the ground under the model. It is made in model coordinates, so the transform
+// from Mesh::scale_and_center applies to it the same as the model and the lines show real sizes.
+
+use crate::config::{Grid, UpAxis};
+use crate::mesh::BoundingBox;
+
+const BED_COLOR: [f32; 3] = [0.82, 0.82, 0.82];
+const LINE_COLOR: [f32; 3] = [0.55, 0.55, 0.55];
+// Width of the lines as a share of the spacing
+const LINE_WIDTH: f32 = 0.06;
+// More lines than this along a side would blur together, so the spacing is made coarser instead
+const MAX_LINES: f32 = 200.0;
+// Times the spacing is made coarser before giving up on the grid, for sizes that are out of range
+const MAX_COARSENING: usize = 16;
+
+#[derive(Copy, Clone)]
+pub struct FloorVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+implement_vertex!(FloorVertex, position, color);
+
+/// Triangles of the bed followed by the lines on it, which are drawn over the bed.
+pub(crate) fn triangles(grid: &Grid, up: UpAxis, bounds: &BoundingBox) -> Vec<FloorVertex> {
+    // Footprint of the model on the ground, and the height of the ground
+    let (min, max, ground) = match up {
+        UpAxis::Z => (
+            [bounds.min.x, bounds.min.y],
+            [bounds.max.x, bounds.max.y],
+            bounds.min.z,
+        ),
+        UpAxis::Y => (
+            [bounds.min.x, bounds.min.z],
+            [bounds.max.x, bounds.max.z],
+            bounds.min.y,
+        ),
+    };
+
+    let mut spacing = grid.spacing;
+    let mut fitted = None;
+    for _ in 0..MAX_COARSENING {
+        let (lo, hi) = match grid.bed {
+            Some(size) => {
+                let center = [0, 1].map(|i| (min[i] + max[i]) / 2.0);
+                (
+                    [0, 1].map(|i| center[i] - size[i] / 2.0),
+                    [0, 1].map(|i| center[i] + size[i] / 2.0),
+                )
+            }
+            // Whole cells around the footprint, with a spare one on each side
+            None => (
+                min.map(|x| ((x / spacing).floor() - 1.0) * spacing),
+                max.map(|x| ((x / spacing).ceil() + 1.0) * spacing),
+            ),
+        };
+        if (hi[0] - lo[0]).max(hi[1] - lo[1]) / spacing <= MAX_LINES {
+            fitted = Some((lo, hi));
+            break;
+        }
+        spacing *= 10.0;
+    }
+    let (lo, hi) = match fitted {
+        Some(fitted) => fitted,
+        None => {
+            warn!("Unable to fit a grid under the model, leaving it out");
+            return Vec::new();
+        }
+    };
+    if spacing != grid.spacing {
+        warn!(
+            "Too many grid lines. Using a spacing of {} instead.",
+            spacing
+        );
+    }
+
+    let mut triangles = Vec::new();
+    let mut quad = |a: [f32; 2], b: [f32; 2], color: [f32; 3]| {
+        let corners = [[a[0], a[1]], [b[0], a[1]], [b[0], b[1]], [a[0], b[1]]];
+        for i in [0, 1, 2, 0, 2, 3] {
+            let [u, v] = corners[i];
+            let position = match up {
+                UpAxis::Z => [u, v, ground],
+                UpAxis::Y => [u, ground, v],
+            };
+            triangles.push(FloorVertex { position, color });
+        }
+    };
+
+    quad(lo, hi, BED_COLOR);
+    let half_width = spacing * LINE_WIDTH / 2.0;
+    for axis in 0..2 {
+        let other = 1 - axis;
+        let count = ((hi[axis] - lo[axis]) / spacing + 0.001).floor() as usize;
+        for i in 0..=count {
+            let x = lo[axis] + i as f32 * spacing;
+            let mut a = lo;
+            let mut b = hi;
+            a[axis] = (x - half_width).max(lo[axis]);
+            b[axis] = (x + half_width).min(hi[axis]);
+            a[other] = lo[other];
+            b[other] = hi[other];
+            quad(a, b, LINE_COLOR);
+        }
+    }
+    triangles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(min: [f32; 3], max: [f32; 3]) -> BoundingBox {
+        BoundingBox {
+            min: min.into(),
+            max: max.into(),
+        }
+    }
+
+    #[test]
+    fn grid_around_model() {
+        let grid = Grid::default();
+        let floor = triangles(
+            &grid,
+            UpAxis::Z,
+            &bounds([3.0, -4.0, 2.0], [25.0, 8.0, 9.0]),
+        );
+        // Everything lies on the ground
+        assert!(floor.iter().all(|v| v.position[2] == 2.0));
+        // The bed covers whole cells from -10 to 40 and -20 to 20
+        let bed = &floor[..6];
+        assert!(bed.iter().any(|v| v.position[..2] == [-10.0, -20.0]));
+        assert!(bed.iter().any(|v| v.position[..2] == [40.0, 20.0]));
+        // 6 lines across and 5 along, two triangles each
+        assert_eq!(6 + (6 + 5) * 6, floor.len());
+    }
+
+    #[test]
+    fn sizes_out_of_range() {
+        let model = bounds([0.0, 0.0, 0.0], [20.0, 20.0, 20.0]);
+        for grid in [
+            Grid {
+                spacing: 10.0,
+                bed: Some([f32::INFINITY, f32::INFINITY]),
+            },
+            Grid {
+                spacing: f32::INFINITY,
+                bed: None,
+            },
+            Grid {
+                spacing: 1e-30,
+                bed: None,
+            },
+        ] {
+            assert!(triangles(&grid, UpAxis::Z, &model).is_empty());
+        }
+    }
+
+    #[test]
+    fn bed_under_model() {
+        let grid = Grid {
+            spacing: 10.0,
+            bed: Some([200.0, 100.0]),
+        };
+        let floor = triangles(
+            &grid,
+            UpAxis::Y,
+            &bounds([0.0, -1.0, 0.0], [20.0, 5.0, 20.0]),
+        );
+        assert!(floor.iter().all(|v| v.position[1] == -1.0));
+        let bed = &floor[..6];
+        assert!(bed.iter().any(|v| v.position == [-90.0, -1.0, -40.0]));
+        assert!(bed.iter().any(|v| v.position == [110.0, -1.0, 60.0]));
+        assert_eq!(6 + (21 + 11) * 6, floor.len());
+    }
+
+    #[test]
+    fn coarse_spacing() {
+        let grid = Grid {
+            spacing: 0.1,
+            bed: Some([1000.0, 1000.0]),
+        };
+        let floor = triangles(&grid, UpAxis::Z, &bounds([0.0; 3], [1.0; 3]));
+        // Made 100 times coarser, which gives 101 lines on each axis
+        assert_eq!(6 + 2 * 101 * 6, floor.len());
+    }
+}
@@ -0,0 +1,141 @@
+// Model Format Detection
+// ======================
+//
+// Thumbnailers often hand us temporary files without an extension, and stdin has none
+// at all, so the format is guessed from the content first and the extension second.
+
+use std::fmt;
+
+// How much of the start of a file is looked at when guessing the format
+pub const SNIFF_LEN: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ModelFormat {
+    Stl,
+    Obj,
+    ThreeMf,
+    Ply,
+    // Both .gltf and .glb, the loader tells them apart
+    Gltf,
+}
+
+impl ModelFormat {
+    /// Format for a file extension, ignoring case.
+    pub fn from_extension(ext: &str) -> Option<ModelFormat> {
+        match ext.to_lowercase().as_str() {
+            "stl" => Some(ModelFormat::Stl),
+            "obj" => Some(ModelFormat::Obj),
+            "3mf" => Some(ModelFormat::ThreeMf),
+            "ply" => Some(ModelFormat::Ply),
+            "gltf" | "glb" => Some(ModelFormat::Gltf),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the start of a model file.
+    ///
+    /// `head` should hold at least the first [`SNIFF_LEN`] bytes of the file, or all of it
+    /// if it is shorter. `len` is the size of the whole file, used to recognize binary STL.
+    pub fn detect(head: &[u8], len: u64) -> Option<ModelFormat> {
+        if head.starts_with(b"PK\x03\x04") {
+            // 3MF is the only zip based format we read
+            return Some(ModelFormat::ThreeMf);
+        }
+        if head.starts_with(b"glTF") {
+            return Some(ModelFormat::Gltf);
+        }
+        if head.starts_with(b"ply\n") || head.starts_with(b"ply\r\n") {
+            return Some(ModelFormat::Ply);
+        }
+        if is_binary_stl(head, len) {
+            return Some(ModelFormat::Stl);
+        }
+
+        let text = String::from_utf8_lossy(head);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        if text.starts_with("solid") {
+            return Some(ModelFormat::Stl);
+        }
+        if text.starts_with('{') && text.contains("\"asset\"") {
+            return Some(ModelFormat::Gltf);
+        }
+        if is_obj(text) {
+            return Some(ModelFormat::Obj);
+        }
+        None
+    }
+}
+
+// Binary STL is an 80 byte header, a triangle count and 50 bytes per triangle
+fn is_binary_stl(head: &[u8], len: u64) -> bool {
+    match head.get(80..84) {
+        Some(count) => {
+            let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as u64;
+            count > 0 && 84 + count * 50 == len
+        }
+        None => false,
+    }
+}
+
+// Every statement has to be an OBJ keyword, and there has to be at least one vertex
+fn is_obj(text: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "v", "vt", "vn", "vp", "f", "l", "p", "o", "g", "s", "mtllib", "usemtl",
+    ];
+    let mut has_vertex = false;
+    // The last line may have been cut off by SNIFF_LEN
+    let complete = match text.rfind('\n') {
+        Some(end) => &text[..end],
+        None => text,
+    };
+    for line in complete.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let keyword = line.split_ascii_whitespace().next().unwrap_or("");
+        if !KEYWORDS.contains(&keyword) {
+            return false;
+        }
+        has_vertex |= keyword == "v";
+    }
+    has_vertex
+}
+
+impl fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ModelFormat::Stl => "STL",
+            ModelFormat::Obj => "OBJ",
+            ModelFormat::ThreeMf => "3MF",
+            ModelFormat::Ply => "PLY",
+            ModelFormat::Gltf => "glTF",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn detect_file(path: &str) -> Option<ModelFormat> {
+        let data = fs::read(path).unwrap();
+        let head = &data[..data.len().min(SNIFF_LEN)];
+        ModelFormat::detect(head, data.len() as u64)
+    }
+
+    #[test]
+    fn test_data() {
+        assert_eq!(Some(ModelFormat::Stl), detect_file("test_data/cube.stl"));
+        assert_eq!(Some(ModelFormat::Obj), detect_file("test_data/cube.obj"));
+        assert_eq!(
+            Some(ModelFormat::ThreeMf),
+            detect_file("test_data/cube.3mf")
+        );
+        assert_eq!(Some(ModelFormat::Ply), detect_file("test_data/cube.ply"));
+        assert_eq!(Some(ModelFormat::Gltf), detect_file("test_data/cube.gltf"));
+        assert_eq!(None, detect_file("test_data/README.md"));
+    }
+}
@@ -4,6 +4,7 @@
  * Apache License 2.0
 */
 
+use crate::error::Error;
 use glium::backend::Context;
 use glium::backend::Facade;
 use glium::framebuffer::SimpleFrameBuffer;
@@ -30,11 +31,11 @@ struct SpriteVertex {
 implement_vertex!(SpriteVertex, position, i_tex_coords);
 
 impl FxaaSystem {
-    pub fn new<F>(facade: &F) -> FxaaSystem
+    pub fn new<F>(facade: &F) -> Result<FxaaSystem, Error>
     where
         F: Facade + ?Sized,
     {
-        FxaaSystem {
+        Ok(FxaaSystem {
             context: facade.get_context().clone(),
 
             vertex_buffer: glium::VertexBuffer::new(
@@ -57,31 +58,33 @@ impl FxaaSystem {
                         i_tex_coords: [1.0, 0.0],
                     },
                 ],
-            )
-            .unwrap(),
+            )?,
 
             index_buffer: glium::index::IndexBuffer::new(
                 facade,
                 glium::index::PrimitiveType::TriangleStrip,
                 &[1u16, 2, 0, 3],
-            )
-            .unwrap(),
+            )?,
 
             program: program!(facade,
                 100 => {
                     vertex: include_str!("shaders/fxaa.vert"),
                     fragment: include_str!("shaders/fxaa.frag"),
                 }
-            )
-            .unwrap(),
+            )?,
 
             target_color: RefCell::new(None),
             target_depth: RefCell::new(None),
-        }
+        })
     }
 }
 
-pub fn draw<T, F, R>(system: &FxaaSystem, target: &mut T, enabled: bool, mut draw: F) -> R
+pub fn draw<T, F, R>(
+    system: &FxaaSystem,
+    target: &mut T,
+    enabled: bool,
+    mut draw: F,
+) -> Result<R, Error>
 where
     T: Surface,
     F: FnMut(&mut SimpleFrameBuffer) -> R,
@@ -119,8 +122,7 @@ where
             &system.context,
             target_dimensions.0,
             target_dimensions.1,
-        )
-        .unwrap();
+        )?;
         *target_color = Some(texture);
     }
     let target_color = target_color.as_ref().unwrap();
@@ -131,16 +133,16 @@ where
             glium::texture::DepthFormat::I24,
             target_dimensions.0,
             target_dimensions.1,
-        )
-        .unwrap();
+        )?;
         *target_depth = Some(texture);
     }
     let target_depth = target_depth.as_ref().unwrap();
 
-    let output = draw(
-        &mut SimpleFrameBuffer::with_depth_buffer(&system.context, target_color, target_depth)
-            .unwrap(),
-    );
+    let output = draw(&mut SimpleFrameBuffer::with_depth_buffer(
+        &system.context,
+        target_color,
+        target_depth,
+    )?);
 
     let uniforms = uniform! {
         tex: target_color,
@@ -148,15 +150,13 @@ where
         resolution: (target_dimensions.0 as f32, target_dimensions.1 as f32)
     };
 
-    target
-        .draw(
-            &system.vertex_buffer,
-            &system.index_buffer,
-            &system.program,
-            &uniforms,
-            &Default::default(),
-        )
-        .unwrap();
-
-    output
+    target.draw(
+        &system.vertex_buffer,
+        &system.index_buffer,
+        &system.program,
+        &uniforms,
+        &Default::default(),
+    )?;
+
+    Ok(output)
 }
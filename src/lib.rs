@@ -1,291 +1,107 @@
 extern crate cgmath;
 #[macro_use]
 extern crate glium;
+extern crate glob;
+extern crate gltf;
 extern crate image;
 extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate mint;
+extern crate walkdir;
 
+mod animation;
+pub mod batch;
+pub mod cache;
 pub mod config;
+#[cfg(unix)]
+pub mod dbus;
+mod decimate;
+mod error;
+mod floor;
+mod format;
 mod fxaa;
 mod mesh;
-
-use cgmath::EuclideanSpace;
-use config::{AAMethod, Config};
-use glium::backend::Facade;
-use glium::glutin::dpi::PhysicalSize;
-use glium::glutin::event_loop::{ControlFlow, EventLoop, EventLoopBuilder};
-use glium::{glutin, CapabilitiesSource, Surface};
-use image::{ImageEncoder, ImageFormat};
+mod ply;
+mod renderer;
+pub mod serve;
+mod sheet;
+mod smooth;
+mod software;
+mod threemf;
+mod thumbnail;
+mod uri;
+
+use config::{Camera, Config, RenderOptions};
+use glium::glutin;
+use glium::glutin::event_loop::ControlFlow;
+use glium::Surface;
+use image::ImageFormat;
 use libc::c_char;
-use mesh::Mesh;
-use std::error::Error;
+use renderer::{create_normal_display, render_pipeline, scene_matrices, MeshBuffers, Pipeline};
 use std::ffi::CStr;
-use std::{io, panic, slice, thread, time};
-
-#[cfg(target_os = "linux")]
-use std::env;
-
-// TODO: Move this stuff to config module
-const CAM_FOV_DEG: f32 = 30.0;
-const CAM_POSITION: cgmath::Point3<f32> = cgmath::Point3 {
-    x: 2.0,
-    y: -4.0,
-    z: 2.0,
-};
-
-fn print_matrix(m: [[f32; 4]; 4]) {
-    for row in &m {
-        debug!("{:.3}\t{:.3}\t{:.3}\t{:.3}", row[0], row[1], row[2], row[3]);
-    }
-    debug!("");
-}
-
-fn print_context_info(display: &glium::backend::Context) {
-    // Print context information
-    info!("GL Version:   {:?}", display.get_opengl_version());
-    info!("GL Version:   {}", display.get_opengl_version_string());
-    info!("GLSL Version: {:?}", display.get_supported_glsl_version());
-    info!("Vendor:       {}", display.get_opengl_vendor_string());
-    info!("Renderer      {}", display.get_opengl_renderer_string());
-    info!("Free GPU Mem: {:?}", display.get_free_video_memory());
-    info!(
-        "Depth Bits:   {:?}\n",
-        display.get_capabilities().depth_bits
-    );
-}
-
-fn create_normal_display(
-    config: &Config,
-) -> Result<(glium::Display, EventLoop<()>), Box<dyn Error>> {
-    let event_loop = EventLoop::new();
-    let window_dim = PhysicalSize::new(config.width, config.height);
-    let window = glutin::window::WindowBuilder::new()
-        .with_title("stl-thumb")
-        .with_inner_size(window_dim)
-        .with_min_inner_size(window_dim)
-        .with_max_inner_size(window_dim)
-        .with_visible(config.visible);
-    let cb = glutin::ContextBuilder::new().with_depth_buffer(24);
-    //.with_multisampling(8);
-    //.with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGlEs, (2, 0)));
-    let display = glium::Display::new(window, cb, &event_loop)?;
-    print_context_info(&display);
-    Ok((display, event_loop))
-}
-
-#[cfg(target_os = "windows")]
-fn create_headless_display(config: &Config) -> Result<glium::HeadlessRenderer, Box<dyn Error>> {
-    use glium::glutin::platform::windows::EventLoopBuilderExtWindows;
+use std::{io, ptr, slice, thread, time};
+use thumbnail::ThumbInfo;
 
-    let event_loop: EventLoop<()> = EventLoopBuilder::new().with_any_thread(true).build();
-    let size = PhysicalSize::new(config.width, config.height);
-    let cb = glutin::ContextBuilder::new();
-    let context = cb.build_headless(&event_loop, size)?;
-
-    let context = unsafe { context.treat_as_current() };
-    let display = glium::backend::glutin::headless::Headless::new(context)?;
-    print_context_info(&display);
-    Ok(display)
-}
+pub use error::Error;
+pub use format::ModelFormat;
+pub use mesh::Mesh;
+pub use renderer::Renderer;
 
 #[cfg(target_os = "linux")]
-fn create_headless_display(config: &Config) -> Result<glium::HeadlessRenderer, Box<dyn Error>> {
-    use glium::glutin::platform::unix::{EventLoopBuilderExtUnix, HeadlessContextExt};
-
-    let size = PhysicalSize::new(config.width, config.height);
-    let cb = glutin::ContextBuilder::new();
-    let context: glium::glutin::Context<glium::glutin::NotCurrent>;
-
-    // Linux requires an elaborate chain of attempts and fallbacks to find the ideal type of opengl context.
-
-    // If there is no X server or Wayland, creating the event loop will fail first.
-    // If this happens we catch the panic and fall back to osmesa software rendering, which doesn't require an event loop.
-    // TODO: Submit PR upstream to stop panicing
-    let event_loop_result: Result<EventLoop<()>, _> =
-        panic::catch_unwind(|| EventLoopBuilder::new().with_any_thread(true).build());
-
-    match event_loop_result {
-        Ok(event_loop) => {
-            context = {
-                // Try surfaceless, headless, and osmesa in that order
-                // This is the procedure recommended in
-                // https://github.com/rust-windowing/glutin/blob/bab33a84dfb094ff65c059400bed7993434638e2/glutin_examples/examples/headless.rs
-                match cb.clone().build_surfaceless(&event_loop) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        warn!("Unable to create surfaceless GL context. Trying headless instead. Reason: {:?}", e);
-                        match cb.clone().build_headless(&event_loop, size) {
-                            Ok(c) => c,
-                            Err(e) => {
-                                warn!("Unable to create headless GL context. Trying osmesa software renderer instead. Reason: {:?}", e);
-                                cb.build_osmesa(size)?
-                            }
-                        }
-                    }
-                }
-            };
-        }
-        Err(e) => {
-            warn!(
-                "No Wayland or X server. Falling back to osmesa software rendering. Reason {:?}",
-                e
-            );
-            context = cb.build_osmesa(size)?;
-        }
-    };
+use std::env;
 
-    let context = unsafe { context.treat_as_current() };
-    let display = glium::backend::glutin::headless::Headless::new(context)?;
-    print_context_info(&display);
-    Ok(display)
+// Load a model with the loading options from config
+pub(crate) fn load_mesh(model_filename: &str, config: &Config) -> Result<Mesh, Error> {
+    let mesh = Mesh::load_with_format(model_filename, config.input_format, config.recalc_normals)?;
+    Ok(prepare_mesh(mesh, config))
 }
 
-fn render_pipeline<F>(
-    display: &F,
-    config: &Config,
-    mesh: &Mesh,
-    framebuffer: &mut glium::framebuffer::SimpleFrameBuffer,
-    texture: &glium::Texture2d,
-) -> image::DynamicImage
-where
-    F: Facade,
-{
-    // Graphics Stuff
-    // ==============
-
-    let params = glium::DrawParameters {
-        depth: glium::Depth {
-            test: glium::draw_parameters::DepthTest::IfLess,
-            write: true,
-            ..Default::default()
-        },
-        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-        ..Default::default()
-    };
-
-    // Load and compile shaders
-    // ------------------------
-
-    let vertex_shader_src = include_str!("shaders/model.vert");
-    let pixel_shader_src = include_str!("shaders/model.frag");
-
-    // TODO: Cache program binary
-    let program = glium::Program::from_source(display, vertex_shader_src, pixel_shader_src, None);
-    let program = match program {
-        Ok(p) => p,
-        Err(glium::CompilationError(err, _)) => {
-            error!("{}", err);
-            panic!("Compiling shaders");
-        }
-        Err(err) => panic!("{}", err),
-    };
-
-    // Send mesh data to GPU
-    // ---------------------
-
-    let vertex_buf = glium::VertexBuffer::new(display, &mesh.vertices).unwrap();
-    let normal_buf = glium::VertexBuffer::new(display, &mesh.normals).unwrap();
-    // Can use NoIndices here because STLs are dumb
-    let indices = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
-
-    // Setup uniforms
-    // --------------
-
-    // Transformation matrix (positions, scales and rotates model)
-    let transform_matrix = mesh.scale_and_center();
-
-    // View matrix (convert to positions relative to camera)
-    let view_matrix = cgmath::Matrix4::look_at_rh(
-        CAM_POSITION,
-        cgmath::Point3::origin(),
-        cgmath::Vector3::unit_z(),
-    );
-    debug!("View:");
-    print_matrix(view_matrix.into());
-
-    // Perspective matrix (give illusion of depth)
-    let perspective_matrix = cgmath::perspective(
-        cgmath::Deg(CAM_FOV_DEG),
-        config.width as f32 / config.height as f32,
-        0.1,
-        1024.0,
-    );
-    debug!("Perspective:");
-    print_matrix(perspective_matrix.into());
-
-    // Direction of light source
-    //let light_dir = [-1.4, 0.4, -0.7f32];
-    let light_dir = [-1.1, 0.4, 1.0f32];
-
-    let uniforms = uniform! {
-        //model: Into::<[[f32; 4]; 4]>::into(transform_matrix),
-        //view: Into::<[[f32; 4]; 4]>::into(view_matrix),
-        modelview: Into::<[[f32; 4]; 4]>::into(view_matrix * transform_matrix),
-        perspective: Into::<[[f32; 4]; 4]>::into(perspective_matrix),
-        u_light: light_dir,
-        ambient_color: config.material.ambient,
-        diffuse_color: config.material.diffuse,
-        specular_color: config.material.specular,
-    };
-
-    // Draw
-    // ----
-
-    // Create FXAA system
-    let fxaa = fxaa::FxaaSystem::new(display);
-    let fxaa_enable = matches!(config.aamethod, AAMethod::FXAA);
-
-    fxaa::draw(&fxaa, framebuffer, fxaa_enable, |target| {
-        // Fills background color and clears depth buffer
-        target.clear_color_and_depth(config.background, 1.0);
-        target
-            .draw(
-                (&vertex_buf, &normal_buf),
-                indices,
-                &program,
-                &uniforms,
-                &params,
-            )
-            .unwrap();
-        // TODO: Shadows
-    });
-
-    // Convert Image
-    // =============
-
-    let pixels: glium::texture::RawImage2d<u8> = texture.read();
-    let img = image::ImageBuffer::from_raw(config.width, config.height, pixels.data.into_owned())
-        .unwrap();
-
-    image::DynamicImage::ImageRgba8(img).flipv()
+// Simplify and smooth a loaded mesh as configured
+pub(crate) fn prepare_mesh(mut mesh: Mesh, config: &Config) -> Mesh {
+    if let Some(max_triangles) = config.max_triangles {
+        mesh.decimate(max_triangles);
+    }
+    if let Some(crease_angle) = config.smooth {
+        mesh.smooth_normals(crease_angle);
+    }
+    mesh
 }
 
-pub fn render_to_window(config: Config) -> Result<(), Box<dyn Error>> {
+pub fn render_to_window(config: Config) -> Result<(), Error> {
     // Get geometry from model file
     // ==========================
-    let mesh = Mesh::load(&config.model_filename, config.recalc_normals)?;
+    let mesh = load_mesh(&config.model_filename, &config)?;
 
     // Create GL context
     // =================
     let (display, event_loop) = create_normal_display(&config)?;
+    let pipeline = Pipeline::new(&display)?;
+    let options = RenderOptions::from(&config);
+    let buffers = MeshBuffers::new(&display, &options, &mesh)?;
+    let matrices = scene_matrices(&options, &mesh);
 
     let sleep_time = time::Duration::from_millis(10);
 
-    let texture = glium::Texture2d::empty(&display, config.width, config.height).unwrap();
+    let texture = glium::Texture2d::empty(&display, config.width, config.height)?;
     let depthtexture =
-        glium::texture::DepthTexture2d::empty(&display, config.width, config.height).unwrap();
+        glium::texture::DepthTexture2d::empty(&display, config.width, config.height)?;
 
     event_loop.run(move |ev, _, control_flow| {
         *control_flow =
             glutin::event_loop::ControlFlow::WaitUntil(std::time::Instant::now() + sleep_time);
-        let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+        let mut framebuffer = match glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
             &display,
             &texture,
             &depthtexture,
-        )
-        .unwrap();
+        ) {
+            Ok(framebuffer) => framebuffer,
+            Err(e) => {
+                error!("Application error: {}", Error::from(e));
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+        };
 
         match ev {
             glutin::event::Event::WindowEvent {
@@ -296,7 +112,19 @@ pub fn render_to_window(config: Config) -> Result<(), Box<dyn Error>> {
                 return;
             }
             glutin::event::Event::NewEvents(glutin::event::StartCause::Init) => {
-                render_pipeline(&display, &config, &mesh, &mut framebuffer, &texture);
+                if let Err(e) = render_pipeline(
+                    &pipeline,
+                    &options,
+                    &mesh,
+                    &buffers,
+                    matrices,
+                    &mut framebuffer,
+                    &texture,
+                ) {
+                    error!("Application error: {}", e);
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
             }
             _ => (),
         }
@@ -318,95 +146,142 @@ pub fn render_to_window(config: Config) -> Result<(), Box<dyn Error>> {
             },
             glium::uniforms::MagnifySamplerFilter::Nearest,
         );
-        target.finish().unwrap();
+        if let Err(e) = target.finish() {
+            error!("Application error: {}", e);
+            *control_flow = ControlFlow::Exit;
+        }
     });
 }
 
-pub fn render_to_image(config: &Config) -> Result<image::DynamicImage, Box<dyn Error>> {
+pub fn render_to_image(config: &Config) -> Result<image::DynamicImage, Error> {
     // Get geometry from model file
     // =========================
-    let mesh = Mesh::load(&config.model_filename, config.recalc_normals)?;
+    let mesh = load_mesh(&config.model_filename, config)?;
 
-    // Create GL context
-    // =================
-    // 1. If not visible create a headless context.
-    // 2. If headless context creation fails, create a normal context with a hidden window.
-    let img: image::DynamicImage = match create_headless_display(config) {
-        Ok(display) => {
-            let texture = glium::Texture2d::empty(&display, config.width, config.height).unwrap();
-            let depthtexture =
-                glium::texture::DepthTexture2d::empty(&display, config.width, config.height)
-                    .unwrap();
-            let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-                &display,
-                &texture,
-                &depthtexture,
+    let mut renderer = Renderer::new(config)?;
+    let options = RenderOptions::from(config);
+    match config.sheet {
+        Some(ref sheet) => renderer.render_sheet(&mesh, &options, sheet),
+        None => renderer.render(&mesh, &options),
+    }
+}
+
+/// Renders the frames of a turntable animation, see [`Renderer::render_turntable`].
+pub fn render_turntable(config: &Config, frames: u32) -> Result<Vec<image::DynamicImage>, Error> {
+    let mesh = load_mesh(&config.model_filename, config)?;
+
+    let mut renderer = Renderer::new(config)?;
+    renderer.render_turntable(&mesh, &RenderOptions::from(config), frames)
+}
+
+pub fn render_to_file(config: &Config) -> Result<(), Error> {
+    // Read before rendering, so a model that changes in the meantime gets a new thumbnail later
+    let info = thumb_info(&config.model_filename, config);
+    match config.turntable {
+        Some(turntable) => {
+            let frames = render_turntable(config, turntable.frames)?;
+            write_animation(
+                &frames,
+                &config.img_filename,
+                &config.format,
+                turntable.frame_delay,
+                info.as_ref(),
             )
-            .unwrap();
-            render_pipeline(&display, config, &mesh, &mut framebuffer, &texture)
         }
-        Err(e) => {
-            warn!(
-                "Unable to create headless GL context. Trying hidden window instead. Reason: {:?}",
-                e
-            );
-            let (display, _) = create_normal_display(config)?;
-            let texture = glium::Texture2d::empty(&display, config.width, config.height).unwrap();
-            let depthtexture =
-                glium::texture::DepthTexture2d::empty(&display, config.width, config.height)
-                    .unwrap();
-            let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
-                &display,
-                &texture,
-                &depthtexture,
-            )
-            .unwrap();
-            render_pipeline(&display, config, &mesh, &mut framebuffer, &texture)
+        None => {
+            let img = render_to_image(config)?;
+            write_image(&img, &config.img_filename, &config.format, info.as_ref())
         }
-    };
-
-    Ok(img)
+    }
 }
 
-pub fn render_to_file(config: &Config) -> Result<(), Box<dyn Error>> {
-    let img = render_to_image(config)?;
+// Details of the model file for the thumbnail metadata. None for stdin, or if they can't be read.
+pub(crate) fn thumb_info(model_filename: &str, config: &Config) -> Option<ThumbInfo> {
+    if model_filename == "-" {
+        return None;
+    }
+    match ThumbInfo::for_model(model_filename, config.input_format) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            warn!("Unable to read thumbnail metadata. Reason: {}", e);
+            None
+        }
+    }
+}
 
-    // Choose output
-    // Write to stdout if user did not specify a file
-    let mut output: Box<dyn io::Write> = match config.img_filename.as_str() {
-        "-" => Box::new(io::stdout()),
-        _ => Box::new(std::fs::File::create(&config.img_filename).unwrap()),
-    };
+// Encode an image and write it to a file, or stdout if the file name is "-".
+// PNG files get the details of the model file in text chunks if there are any.
+pub(crate) fn write_image(
+    img: &image::DynamicImage,
+    img_filename: &str,
+    format: &ImageFormat,
+    info: Option<&ThumbInfo>,
+) -> Result<(), Error> {
+    let buff = encode_image(img, format, info)?;
+    write_output(&buff, img_filename)
+}
 
+pub(crate) fn encode_image(
+    img: &image::DynamicImage,
+    format: &ImageFormat,
+    info: Option<&ThumbInfo>,
+) -> Result<Vec<u8>, Error> {
     // write_to() requires a seekable writer for performance reasons.
     // So we create an in-memory buffer and then dump that to the output.
     // I wonder if it would be better to use std::io::BufWriter for writing files instead.
     let mut buff: Vec<u8> = Vec::new();
-    let mut cursor = io::Cursor::new(&mut buff);
 
     // Encode image with specified format
     // If encoding a PNG image, use fastest compression method
     // Not sure if this is really necessary. Fast is the default anyways.
-    match config.format {
+    // PNGs are written with the png crate, because image can't add text chunks.
+    match format {
         ImageFormat::Png => {
-            let encoder = image::codecs::png::PngEncoder::new_with_quality(
-                &mut cursor,
-                image::codecs::png::CompressionType::Fast,
-                //image::codecs::png::CompressionType::Default,
-                image::codecs::png::FilterType::Adaptive,
-            );
-            encoder.write_image(
-                img.as_bytes(),
-                config.width,
-                config.height,
-                img.color().into(),
-            )?;
+            let img = img.to_rgba8();
+            let mut encoder = png::Encoder::new(&mut buff, img.width(), img.height());
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(png::Compression::Fast);
+            encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+            for (keyword, text) in info.iter().flat_map(|info| info.text_chunks()) {
+                encoder.add_text_chunk(keyword.to_string(), text)?;
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(img.as_raw())?;
+            writer.finish()?;
         }
-        _ => img.write_to(&mut cursor, config.format.to_owned())?,
+        // JPEG has no alpha channel
+        ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut io::Cursor::new(&mut buff), ImageFormat::Jpeg)?,
+        _ => img.write_to(&mut io::Cursor::new(&mut buff), format.to_owned())?,
     }
-    //img.write_to(&mut cursor, config.format.to_owned())?;
+    //img.write_to(&mut cursor, format.to_owned())?;
+
+    Ok(buff)
+}
+
+// Encode frames as an animation and write it to a file, or stdout if the file name is "-"
+pub(crate) fn write_animation(
+    frames: &[image::DynamicImage],
+    img_filename: &str,
+    format: &ImageFormat,
+    frame_delay: u16,
+    info: Option<&ThumbInfo>,
+) -> Result<(), Error> {
+    let buff = animation::encode(frames, *format, frame_delay, info)?;
+    write_output(&buff, img_filename)
+}
 
-    output.write_all(&buff)?;
+fn write_output(buff: &[u8], img_filename: &str) -> Result<(), Error> {
+    // Choose output
+    // The file is only created once encoding succeeded, so failures don't leave empty files behind
+    // Write to stdout if user did not specify a file
+    let mut output: Box<dyn io::Write> = match img_filename {
+        "-" => Box::new(io::stdout()),
+        _ => Box::new(std::fs::File::create(img_filename)?),
+    };
+
+    output.write_all(buff)?;
     output.flush()?;
 
     Ok(())
@@ -444,6 +319,37 @@ pub unsafe extern "C" fn render_to_buffer(
     width: u32,
     height: u32,
     model_filename_c: *const c_char,
+) -> bool {
+    unsafe { render_to_buffer_with_camera(buf_ptr, width, height, model_filename_c, ptr::null()) }
+}
+
+/// Same as [`render_to_buffer`], but looking at the model from `camera`.
+///
+/// `camera` may be null to use the default view. [`camera_preset`] fills in a camera
+/// for one of the named views. Returns `false` if the camera can't be rendered with, like
+/// one with a field of view of 180 degrees or more.
+///
+/// # Example in C
+/// ```c
+/// Camera camera;
+/// camera_preset("front", &camera);
+/// camera.elevation = 15.0;
+///
+/// render_to_buffer_with_camera(buf_ptr, width, height, model_filename_c, &camera);
+/// ```
+///
+/// # Safety
+///
+/// * `buf_ptr` _must_ point to a valid initialized buffer, at least `width * height * 4` bytes long.
+/// * `model_filename_c` must point to a valid null-terminated string.
+/// * `camera` must be null or point to a valid `Camera`.
+#[no_mangle]
+pub unsafe extern "C" fn render_to_buffer_with_camera(
+    buf_ptr: *mut u8,
+    width: u32,
+    height: u32,
+    model_filename_c: *const c_char,
+    camera: *const Camera,
 ) -> bool {
     // Workaround for issues with OpenGL 3.1 on Mesa 18.3
     #[cfg(target_os = "linux")]
@@ -454,7 +360,13 @@ pub unsafe extern "C" fn render_to_buffer(
         error!("Image buffer pointer is null");
         return false;
     };
-    let buf_size = (width * height * 4) as usize;
+    let buf_size = match width.checked_mul(height).and_then(|s| s.checked_mul(4)) {
+        Some(s) => s as usize,
+        None => {
+            error!("Image size {}x{} is too large", width, height);
+            return false;
+        }
+    };
     let buf = unsafe { slice::from_raw_parts_mut(buf_ptr, buf_size) };
 
     // Check validity of provided file path string
@@ -473,21 +385,32 @@ pub unsafe extern "C" fn render_to_buffer(
         }
     };
 
+    let camera = unsafe { camera.as_ref() }.copied().unwrap_or_default();
+    if let Err(e) = camera.check() {
+        error!("{}", e);
+        return false;
+    }
+
     // Setup configuration for the renderer
     let config = Config {
         model_filename: model_filename_str.to_string(),
         width,
         height,
+        camera,
         ..Default::default()
     };
 
     // Render
 
     // Run renderer in seperate thread so OpenGL problems do not crash caller
-    let render_thread = thread::spawn(move || render_to_image(&config).unwrap());
+    let render_thread = thread::spawn(move || render_to_image(&config));
 
     let img = match render_thread.join() {
-        Ok(s) => s,
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            error!("Application error: {}", e);
+            return false;
+        }
         Err(e) => {
             error!("Application error: {:?}", e);
             return false;
@@ -506,12 +429,40 @@ pub unsafe extern "C" fn render_to_buffer(
     true
 }
 
+/// Fills `camera` with one of the named views: iso, front, back, left, right, top or bottom.
+///
+/// Returns `false` and leaves `camera` unchanged if the name is not known.
+///
+/// # Safety
+///
+/// * `name_c` must point to a valid null-terminated string.
+/// * `camera` must point to a valid `Camera`.
+#[no_mangle]
+pub unsafe extern "C" fn camera_preset(name_c: *const c_char, camera: *mut Camera) -> bool {
+    if name_c.is_null() || camera.is_null() {
+        error!("Camera preset pointer is null");
+        return false;
+    }
+    let name = unsafe { CStr::from_ptr(name_c) };
+    match name.to_str().ok().and_then(Camera::preset) {
+        Some(preset) => {
+            unsafe { *camera = preset };
+            true
+        }
+        None => {
+            error!("Unknown camera preset {:?}", name);
+            false
+        }
+    }
+}
+
 // TODO: Move tests to their own file
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
     use std::io::ErrorKind;
+    use std::path::Path;
 
     #[test]
     fn cube_stl() {
@@ -538,6 +489,41 @@ mod tests {
         assert_ne!(0, size);
     }
 
+    #[test]
+    fn cube_jpeg() {
+        let img_filename = "cube-stl.jpg".to_string();
+        let config = Config {
+            model_filename: "test_data/cube.stl".to_string(),
+            img_filename: img_filename.clone(),
+            format: image::ImageFormat::Jpeg,
+            ..Default::default()
+        };
+
+        match fs::remove_file(&img_filename) {
+            Ok(_) => (),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => (),
+            Err(_) => {
+                panic!("Couldn't clean files before testing");
+            }
+        }
+
+        render_to_file(&config).expect("Error in render function");
+
+        // JPEG has no alpha channel, so the image is written without it
+        let data = fs::read(img_filename).expect("No file created");
+        assert_eq!(
+            image::ImageFormat::Jpeg,
+            image::guess_format(&data).unwrap()
+        );
+        assert_eq!(
+            (config.width, config.height),
+            image::load_from_memory(&data)
+                .unwrap()
+                .to_rgb8()
+                .dimensions()
+        );
+    }
+
     #[test]
     fn cube_obj() {
         let img_filename = "cube-obj.png".to_string();
@@ -563,6 +549,31 @@ mod tests {
         assert_ne!(0, size);
     }
 
+    #[test]
+    fn model_uri() {
+        let uri = uri::file_uri(Path::new("test_data/cube.obj")).unwrap();
+        let mesh = Mesh::load(&uri, false).unwrap();
+        // Materials are found next to the decoded path
+        assert_eq!(mesh.vertices.len(), mesh.colors.len());
+        assert!(mesh.colors.iter().all(|c| c.is_some()));
+        assert!(matches!(
+            Mesh::load("sftp://host/cube.stl", false),
+            Err(Error::InvalidUri(_))
+        ));
+    }
+
+    #[test]
+    fn obj_materials() {
+        let mesh = Mesh::load("test_data/cube.obj", false).unwrap();
+        let expected = config::Material {
+            ambient: [0.0, 0.0, 0.0],
+            diffuse: [0.64, 0.52, 0.1],
+            specular: [0.5, 0.5, 0.5],
+        };
+        assert_eq!(mesh.vertices.len(), mesh.colors.len());
+        assert!(mesh.colors.iter().all(|c| *c == Some(expected)));
+    }
+
     #[test]
     fn cube_3mf() {
         let img_filename = "cube-3mf.png".to_string();
@@ -587,4 +598,188 @@ mod tests {
 
         assert_ne!(0, size);
     }
+
+    #[test]
+    fn cube_gltf() {
+        let img_filename = "cube-gltf.png".to_string();
+        let config = Config {
+            model_filename: "test_data/cube.gltf".to_string(),
+            img_filename: img_filename.clone(),
+            format: image::ImageFormat::Png,
+            ..Default::default()
+        };
+
+        match fs::remove_file(&img_filename) {
+            Ok(_) => (),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => (),
+            Err(_) => {
+                panic!("Couldn't clean files before testing");
+            }
+        }
+
+        render_to_file(&config).expect("Error in render function");
+
+        let size = fs::metadata(img_filename).expect("No file created").len();
+
+        assert_ne!(0, size);
+    }
+
+    #[test]
+    fn gltf_transforms_and_color() {
+        let mesh = Mesh::load("test_data/cube.gltf", false).unwrap();
+        // Unit cube, scaled by 10 in the parent node after being centered in the child
+        assert_eq!(-5.0, mesh.bounds.min.x);
+        assert_eq!(5.0, mesh.bounds.max.y);
+        assert_eq!(10.0, mesh.bounds.max.z);
+        assert_eq!(mesh.vertices.len(), mesh.colors.len());
+        assert!(mesh.colors.iter().all(|c| c.is_some()));
+    }
+
+    #[test]
+    fn cube_ply() {
+        let img_filename = "cube-ply.png".to_string();
+        let config = Config {
+            model_filename: "test_data/cube.ply".to_string(),
+            img_filename: img_filename.clone(),
+            format: image::ImageFormat::Png,
+            ..Default::default()
+        };
+
+        match fs::remove_file(&img_filename) {
+            Ok(_) => (),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => (),
+            Err(_) => {
+                panic!("Couldn't clean files before testing");
+            }
+        }
+
+        render_to_file(&config).expect("Error in render function");
+
+        let size = fs::metadata(img_filename).expect("No file created").len();
+
+        assert_ne!(0, size);
+    }
+
+    #[test]
+    fn detect_format_without_extension() {
+        let model_filename = std::env::temp_dir().join("stl-thumb-test-model");
+        fs::copy("test_data/cube.3mf", &model_filename).unwrap();
+        let mesh = Mesh::load(model_filename.to_str().unwrap(), false);
+        fs::remove_file(&model_filename).unwrap();
+        assert!(!mesh.unwrap().vertices.is_empty());
+    }
+
+    #[test]
+    fn input_format_override() {
+        // Detection is skipped, so the STL is handed to the PLY parser
+        match Mesh::load_with_format("test_data/cube.stl", Some(ModelFormat::Ply), false) {
+            Err(Error::Parse { format, .. }) => assert_eq!("PLY", format),
+            _ => panic!("Expected PLY parse error"),
+        }
+    }
+
+    #[test]
+    fn unsupported_format() {
+        match Mesh::load("test_data/README.md", false) {
+            Err(Error::UnsupportedFormat(ext)) => assert_eq!("md", ext),
+            _ => panic!("Expected UnsupportedFormat error"),
+        }
+    }
+
+    #[test]
+    fn shared_vertices() {
+        let mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        // The two triangles of each side share their diagonal
+        assert_eq!(12 * 3, mesh.indices.len());
+        assert_eq!(6 * 4, mesh.vertices.len());
+        assert_eq!(mesh.vertices.len(), mesh.normals.len());
+        assert!(mesh.triangles().flatten().all(|i| i < mesh.vertices.len()));
+    }
+
+    #[test]
+    fn turntable() {
+        let config = Config {
+            model_filename: "test_data/cube.stl".to_string(),
+            width: 64,
+            height: 64,
+            backend: config::Backend::Software,
+            ..Default::default()
+        };
+        let frames = render_turntable(&config, 8).unwrap();
+        assert_eq!(8, frames.len());
+        assert!(frames
+            .iter()
+            .all(|img| img.width() == 64 && img.height() == 64));
+        // The box looks the same after half a turn, and is framed the same way each time
+        assert_eq!(frames[0].as_bytes(), frames[4].as_bytes());
+        assert_ne!(frames[0].as_bytes(), frames[1].as_bytes());
+    }
+
+    #[test]
+    fn contact_sheet() {
+        let camera = Camera::default();
+        let config = Config {
+            model_filename: "test_data/cube.stl".to_string(),
+            width: 128,
+            height: 64,
+            backend: config::Backend::Software,
+            sheet: config::Sheet::from_presets(&["front", "top"], &camera, 2),
+            ..Default::default()
+        };
+        let img = render_to_image(&config).unwrap().to_rgba8();
+        assert_eq!((128, 64), img.dimensions());
+        // The model is in the middle of both cells, which are framed separately
+        for x in [32, 96] {
+            assert_eq!(255, img.get_pixel(x, 32)[3]);
+            assert_eq!(0, img.get_pixel(x, 1)[3]);
+        }
+    }
+
+    #[test]
+    fn png_metadata() {
+        let img_filename = "cube-metadata.png".to_string();
+        let config = Config {
+            model_filename: "test_data/cube.stl".to_string(),
+            img_filename: img_filename.clone(),
+            width: 32,
+            height: 32,
+            backend: config::Backend::Software,
+            ..Default::default()
+        };
+        render_to_file(&config).expect("Error in render function");
+
+        let reader = png::Decoder::new(fs::File::open(&img_filename).unwrap())
+            .read_info()
+            .unwrap();
+        let text = &reader.info().uncompressed_latin1_text;
+        let chunk = |keyword: &str| {
+            text.iter()
+                .find(|chunk| chunk.keyword == keyword)
+                .map(|chunk| chunk.text.clone())
+        };
+        let model = fs::metadata("test_data/cube.stl").unwrap();
+        let uri = chunk("Thumb::URI").unwrap();
+        assert!(uri.starts_with("file:///") && uri.ends_with("/test_data/cube.stl"));
+        assert_eq!(Some(model.len().to_string()), chunk("Thumb::Size"));
+        assert_eq!(Some("model/stl".to_string()), chunk("Thumb::Mimetype"));
+        let mtime: u64 = chunk("Thumb::MTime").unwrap().parse().unwrap();
+        let modified = model.modified().unwrap();
+        assert_eq!(
+            modified.duration_since(time::UNIX_EPOCH).unwrap().as_secs(),
+            mtime
+        );
+        assert!(chunk("Software").unwrap().starts_with("stl-thumb"));
+    }
+
+    #[test]
+    fn empty_stl() {
+        // Binary STL header followed by a triangle count of zero
+        let mut stl = vec![0u8; 80];
+        stl.extend_from_slice(&0u32.to_le_bytes());
+
+        match Mesh::from_stl(io::Cursor::new(stl), false) {
+            Err(Error::EmptyModel) => (),
+            _ => panic!("Expected EmptyModel error"),
+        }
+    }
 }
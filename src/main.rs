@@ -5,7 +5,8 @@ extern crate stderrlog;
 extern crate stl_thumb;
 
 use std::process;
-use stl_thumb::config::Config;
+use stl_thumb::batch::{Job, Outcome};
+use stl_thumb::config::{Config, Mode};
 
 #[cfg(target_os = "linux")]
 use std::env;
@@ -25,6 +26,66 @@ fn main() {
         .init()
         .unwrap();
 
+    let report = |job: &Job, outcome: &Outcome| match outcome {
+        Outcome::Rendered => println!("{} -> {}", job.input.display(), job.output.display()),
+        Outcome::UpToDate => println!("{} is up to date", job.output.display()),
+        Outcome::Failed(e) => println!("{} failed: {}", job.input.display(), e),
+        Outcome::FailedBefore => println!(
+            "{} failed before and is unchanged, use --force to try again",
+            job.input.display()
+        ),
+    };
+    let result = match config.mode {
+        Mode::Batch(ref batch) => Some(stl_thumb::batch::run(&config, batch, report)),
+        Mode::Cache(ref cache) => Some(stl_thumb::cache::run(&config, cache, report)),
+        Mode::Dbus(ref dbus) => {
+            #[cfg(unix)]
+            let result = stl_thumb::dbus::run(&config, dbus);
+            #[cfg(not(unix))]
+            let result: Result<(), stl_thumb::Error> = {
+                let _ = dbus;
+                Err(stl_thumb::Error::Bus(
+                    "Not supported on this platform".to_string(),
+                ))
+            };
+            if let Err(e) = result {
+                error!("Application error: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Mode::Serve(ref serve) => {
+            if let Err(e) = stl_thumb::serve::run(&config, serve) {
+                error!("Application error: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Mode::Single => None,
+    };
+    if let Some(result) = result {
+        match result {
+            Ok(summary) => {
+                print!(
+                    "{} rendered, {} up to date, {} failed",
+                    summary.rendered, summary.up_to_date, summary.failed
+                );
+                if summary.failed_before > 0 {
+                    print!(", {} failed before", summary.failed_before);
+                }
+                println!();
+                if summary.failed > 0 {
+                    process::exit(1);
+                }
+            }
+            Err(e) => {
+                error!("Application error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     info!("MODEL File: {}", config.model_filename);
     info!("IMG File: {}", config.img_filename);
 
@@ -3,10 +3,21 @@ extern crate cgmath;
 extern crate stl_io;
 extern crate tobj;
 
-use std::error::Error;
-use std::fs::File;
+use crate::config::{Material, AMBIENT_LIGHT};
+use crate::decimate;
+use crate::error::Error;
+use crate::format::{ModelFormat, SNIFF_LEN};
+use crate::ply;
+use crate::smooth;
+use crate::threemf;
+use crate::uri;
+use cgmath::{Matrix, SquareMatrix};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::io::BufReader;
 use std::io::{Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
 use std::{fmt, io};
 
 use self::stl_io::{Triangle, Vector};
@@ -14,9 +25,12 @@ use self::stl_io::{Triangle, Vector};
 use self::ahash::AHashMap;
 use self::tobj::LoadOptions;
 
+// File extensions of the formats Mesh::load can read
+pub const MODEL_EXTENSIONS: &[&str] = &["obj", "stl", "3mf", "gltf", "glb", "ply"];
+
 #[derive(Copy, Clone)]
 pub struct Vertex {
-    position: [f32; 3],
+    pub position: [f32; 3],
     //texcoords: [f32; 2],
 }
 
@@ -25,11 +39,14 @@ implement_vertex!(Vertex, position);
 
 #[derive(Debug, Copy, Clone)]
 pub struct Normal {
-    normal: [f32; 3],
+    pub normal: [f32; 3],
 }
 
 implement_vertex!(Normal, normal);
 
+// Per vertex colors, for models that provide their own
+implement_vertex!(Material, ambient, diffuse, specular);
+
 #[derive(Clone)]
 pub struct BoundingBox {
     pub min: cgmath::Point3<f32>,
@@ -99,122 +116,283 @@ impl fmt::Display for BoundingBox {
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub normals: Vec<Normal>,
-    pub indices: Vec<usize>,
+    // Either empty or one entry per vertex. Vertices without a color use the configured material.
+    pub colors: Vec<Option<Material>>,
+    // Three per triangle. Triangles share vertices where they have the same attributes.
+    pub indices: Vec<u32>,
     pub bounds: BoundingBox,
     model_had_normals: bool,
 }
 
+// Position, normal and color of a vertex, compared bit for bit
+type VertexKey = ([u32; 3], [u32; 3], Option<[u32; 9]>);
+
+// Bits of a float, where -0.0 is the same as 0.0
+pub(crate) fn key_bits(x: f32) -> u32 {
+    (x + 0.0).to_bits()
+}
+
+pub(crate) fn material_bits(material: &Material) -> [u32; 9] {
+    let mut bits = [0; 9];
+    let values = [material.ambient, material.diffuse, material.specular];
+    for (b, x) in bits.iter_mut().zip(values.iter().flatten()) {
+        *b = key_bits(*x);
+    }
+    bits
+}
+
+// Collects the triangles of a model into an indexed mesh
+struct MeshBuilder {
+    vertices: Vec<Vertex>,
+    normals: Vec<Normal>,
+    colors: Vec<Option<Material>>,
+    indices: Vec<u32>,
+    bounds: Option<BoundingBox>,
+    model_had_normals: bool,
+    unique: AHashMap<VertexKey, u32>,
+}
+
+impl MeshBuilder {
+    fn new(model_had_normals: bool) -> MeshBuilder {
+        MeshBuilder {
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            colors: Vec::new(),
+            indices: Vec::new(),
+            bounds: None,
+            model_had_normals,
+            unique: AHashMap::new(),
+        }
+    }
+
+    // Add a triangle that may have its own colors
+    fn push_tri(
+        &mut self,
+        vertices: &[Vector<f32>; 3],
+        normals: [Normal; 3],
+        colors: [Option<Material>; 3],
+    ) {
+        for i in 0..3 {
+            let v = &vertices[i];
+            match self.bounds {
+                Some(ref mut bounds) => bounds.expand(v),
+                None => self.bounds = Some(BoundingBox::new(v)),
+            }
+            let key = (
+                [v[0], v[1], v[2]].map(key_bits),
+                normals[i].normal.map(key_bits),
+                colors[i].as_ref().map(material_bits),
+            );
+            let next = self.vertices.len() as u32;
+            let index = *self.unique.entry(key).or_insert(next);
+            if index == next {
+                self.vertices.push(Vertex {
+                    position: (*v).into(),
+                });
+                self.normals.push(normals[i]);
+                // Colors are only stored once the first colored vertex shows up
+                if colors[i].is_some() || !self.colors.is_empty() {
+                    self.colors.resize(next as usize, None);
+                    self.colors.push(colors[i]);
+                }
+            }
+            self.indices.push(index);
+        }
+    }
+
+    // Add an STL triangle, which has a single normal
+    fn process_tri(&mut self, tri: &stl_io::Triangle, recalc_normals: bool) {
+        // Use normal from STL file if it is provided, otherwise calculate it ourselves
+        let n: Normal;
+        if recalc_normals || (tri.normal == stl_io::Vector::new([0.0, 0.0, 0.0])) {
+            self.model_had_normals = false;
+            n = normal(tri);
+        } else {
+            n = Normal {
+                normal: tri.normal.into(),
+            };
+        }
+        //debug!("{:?}",tri.normal);
+        self.push_tri(&tri.vertices, [n, n, n], [None; 3]);
+    }
+
+    fn finish(self) -> Result<Mesh, Error> {
+        let bounds = self.bounds.ok_or(Error::EmptyModel)?;
+        info!(
+            "Vertices:\t{} for {} triangles",
+            self.vertices.len(),
+            self.indices.len() / 3
+        );
+        Ok(Mesh {
+            vertices: self.vertices,
+            normals: self.normals,
+            colors: self.colors,
+            indices: self.indices,
+            bounds,
+            model_had_normals: self.model_had_normals,
+        })
+    }
+}
+
 impl Mesh {
-    // Load mesh data from file (if provided) or stdin
-    pub fn load(model_filename: &str, recalc_normals: bool) -> Result<Mesh, Box<dyn Error>> {
-        // TODO: Add support for URIs instead of plain file names
-        // https://developer.gnome.org/integration-guide/stable/thumbnailer.html.en
+    // Load mesh data from file (if provided) or stdin.
+    // The file can also be given as a file:// URI.
+    pub fn load(model_filename: &str, recalc_normals: bool) -> Result<Mesh, Error> {
+        Mesh::load_with_format(model_filename, None, recalc_normals)
+    }
+
+    // Same as load, but with a format that overrides detection
+    pub fn load_with_format(
+        model_filename: &str,
+        format: Option<ModelFormat>,
+        recalc_normals: bool,
+    ) -> Result<Mesh, Error> {
+        Mesh::load_within(model_filename, format, None, recalc_normals)
+    }
+
+    /// Same as load_with_format, but files the model references, like OBJ materials or glTF
+    /// buffers, must be inside `root` if it's given. Used for models from untrusted sources.
+    pub fn load_within(
+        model_filename: &str,
+        format: Option<ModelFormat>,
+        root: Option<&Path>,
+        recalc_normals: bool,
+    ) -> Result<Mesh, Error> {
         match model_filename {
             "-" => {
                 // create_stl_reader requires Seek, so we must read the entire stream into memory before proceeding.
                 // So I guess this can just consume all RAM if it gets bad input. Hmmm....
                 let mut input_buffer = Vec::new();
                 io::stdin().read_to_end(&mut input_buffer)?;
-                Mesh::from_stl(Cursor::new(input_buffer), recalc_normals)
+                Mesh::from_buffer(input_buffer, format, recalc_normals)
             }
             _ => {
-                let model_filename = std::path::Path::new(model_filename);
+                let model_path = uri::model_path(model_filename)?;
                 // TODO: Try BufReader and see if it's faster
-                let model_file = File::open(model_filename)?;
-                Ok(
-                    match model_filename
-                        .extension()
-                        .and_then(std::ffi::OsStr::to_str)
-                        .unwrap_or("")
-                        .to_lowercase()
-                        .as_str()
-                    {
-                        "obj" => Mesh::from_obj(model_file, recalc_normals)?,
-                        "stl" => Mesh::from_stl(model_file, recalc_normals)?,
-                        "3mf" => Mesh::from_3mf(model_file, recalc_normals)?,
-                        _ => unimplemented!("Format not supported"),
-                    },
+                let mut model_file = File::open(&model_path)?;
+                let format = match format {
+                    Some(format) => format,
+                    None => Mesh::detect_file(&mut model_file, &model_path)?,
+                };
+                let root = root.map(fs::canonicalize).transpose()?;
+                Mesh::from_format(
+                    model_file,
+                    format,
+                    model_path.parent(),
+                    root.as_deref(),
+                    recalc_normals,
                 )
             }
         }
     }
 
-    pub fn from_3mf<R>(model_file: R, _recalc_normals: bool) -> Result<Mesh, Box<dyn Error>>
+    /// Loads a model read into memory. The format is detected from the content if it's not given.
+    pub fn from_buffer(
+        buffer: Vec<u8>,
+        format: Option<ModelFormat>,
+        recalc_normals: bool,
+    ) -> Result<Mesh, Error> {
+        let format = match format {
+            Some(format) => format,
+            None => {
+                ModelFormat::detect(&buffer, buffer.len() as u64).ok_or(Error::UnknownFormat)?
+            }
+        };
+        Mesh::from_format(Cursor::new(buffer), format, None, None, recalc_normals)
+    }
+
+    // Guess the format from the content, or the extension if the content is not recognized
+    pub(crate) fn detect_file(
+        model_file: &mut File,
+        model_filename: &Path,
+    ) -> Result<ModelFormat, Error> {
+        let len = model_file.metadata()?.len();
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        model_file
+            .by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)?;
+        model_file.rewind()?;
+
+        let ext = model_filename
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)
+            .unwrap_or("")
+            .to_lowercase();
+        let format = ModelFormat::detect(&head, len)
+            .or_else(|| ModelFormat::from_extension(&ext))
+            .ok_or(Error::UnsupportedFormat(ext))?;
+        info!("Model format:\t{}", format);
+        Ok(format)
+    }
+
+    /// Reads a model in the given format.
+    ///
+    /// `base` is the directory other files referenced by the model are loaded from, such as
+    /// OBJ materials or glTF buffers. If `root` is given, references to files outside of it
+    /// are refused. It has to be canonical.
+    pub fn from_format<R>(
+        model_file: R,
+        format: ModelFormat,
+        base: Option<&Path>,
+        root: Option<&Path>,
+        recalc_normals: bool,
+    ) -> Result<Mesh, Error>
     where
         R: Read + Seek,
     {
-        let models = threemf::read(model_file)?;
-
-        let mut result = None;
+        match format {
+            ModelFormat::Stl => Mesh::from_stl(model_file, recalc_normals),
+            ModelFormat::Obj => Mesh::from_obj(model_file, base, root, recalc_normals),
+            ModelFormat::ThreeMf => Mesh::from_3mf(model_file, recalc_normals),
+            ModelFormat::Ply => Mesh::from_ply(model_file, recalc_normals),
+            ModelFormat::Gltf => Mesh::from_gltf(model_file, base, root, recalc_normals),
+        }
+    }
 
-        let vertex_translator = |vertex: &threemf::model::Vertex| {
-            stl_io::Vertex::new([vertex.x as f32, vertex.y as f32, vertex.z as f32])
-        };
+    pub fn from_3mf<R>(model_file: R, _recalc_normals: bool) -> Result<Mesh, Error>
+    where
+        R: Read + Seek,
+    {
+        let mut mesh = MeshBuilder::new(false);
 
-        // Combine all the models into a single mesh.
-        for model in models {
-            for object in model.resources.object {
-                if let Some(mesh) = object.mesh {
-                    for triangle in &mesh.triangles.triangle {
-                        // Re-use `Mesh::process_tri`, which creates new vertices for every
-                        // triangle.
-                        // Possible optimization: re-use triangles instead.
-                        let triangle = stl_io::Triangle {
-                            normal: stl_io::Normal::new([1f32, 0f32, 0f32]),
-                            vertices: [
-                                vertex_translator(&mesh.vertices.vertex[triangle.v1]),
-                                vertex_translator(&mesh.vertices.vertex[triangle.v2]),
-                                vertex_translator(&mesh.vertices.vertex[triangle.v3]),
-                            ],
-                        };
-                        result
-                            .get_or_insert_with(|| Mesh {
-                                vertices: Vec::new(),
-                                normals: Vec::new(),
-                                indices: Vec::new(),
-                                bounds: BoundingBox::new(&triangle.vertices[0]),
-                                model_had_normals: false,
-                            })
-                            .process_tri(&triangle, true);
-                    }
-                }
-            }
+        // Every build item, in its place on the build plate
+        for threemf::Triangle { vertices, colors } in threemf::read(model_file)? {
+            let triangle = stl_io::Triangle {
+                normal: stl_io::Normal::new([0.0, 0.0, 0.0]),
+                vertices: vertices.map(Vector::new),
+            };
+            let n = normal(&triangle);
+            mesh.push_tri(
+                &triangle.vertices,
+                [n, n, n],
+                colors.map(|c| c.map(Material::from_color)),
+            );
         }
 
-        Ok(result.unwrap())
+        mesh.finish()
     }
 
-    pub fn from_stl<R>(mut model_file: R, recalc_normals: bool) -> Result<Mesh, Box<dyn Error>>
+    pub fn from_stl<R>(mut model_file: R, recalc_normals: bool) -> Result<Mesh, Error>
     where
         R: Read + Seek,
     {
         //let model = stl_io::read_stl(&mut model_file)?;
         //debug!("{:?}", model);
-        let mut stl_iter = stl_io::create_stl_reader(&mut model_file)?;
-
-        // Get starting point for finding bounding box
-        // TODO: Remove unwraps so lib can fail gracefully instead of panicing
-        let t1 = stl_iter.next().unwrap().unwrap();
-        let v1 = t1.vertices[0];
-
-        let mut mesh = Mesh {
-            vertices: Vec::new(),
-            normals: Vec::new(),
-            indices: Vec::new(),
-            bounds: BoundingBox::new(&v1),
-            model_had_normals: true,
-        };
+        let stl_iter =
+            stl_io::create_stl_reader(&mut model_file).map_err(|e| Error::parse("STL", e))?;
 
+        let mut mesh = MeshBuilder::new(true);
         let mut face_count = 0;
-        mesh.process_tri(&t1, recalc_normals);
-        face_count += 1;
 
         for triangle in stl_iter {
-            mesh.process_tri(&triangle?, recalc_normals);
+            let triangle = triangle.map_err(|e| Error::parse("STL", e))?;
+            mesh.process_tri(&triangle, recalc_normals);
             face_count += 1;
             //debug!("{:?}",triangle);
         }
 
+        let mesh = mesh.finish()?;
         if !mesh.model_had_normals {
             warn!("STL file missing surface normals");
         }
@@ -226,64 +404,78 @@ impl Mesh {
         Ok(mesh)
     }
 
-    pub fn from_obj(obj_file: File, _recalc_normals: bool) -> Result<Mesh, Box<dyn Error>> {
+    pub fn from_obj<R>(
+        obj_file: R,
+        base: Option<&Path>,
+        root: Option<&Path>,
+        _recalc_normals: bool,
+    ) -> Result<Mesh, Error>
+    where
+        R: Read,
+    {
         let mut model = BufReader::new(obj_file);
-        let (models, _) = tobj::load_obj_buf(
+        // Missing materials only leave the model uncolored, but refused ones fail it
+        let refused = Cell::new(None);
+        let (models, materials) = tobj::load_obj_buf(
             &mut model,
             &LoadOptions {
                 single_index: true,
                 triangulate: true,
                 ..LoadOptions::default()
             },
-            |_| Ok((Vec::new(), AHashMap::new())),
-        )?;
-        let first_mesh = &models.first().ok_or("Empty Model")?.mesh;
-        let mut first_vertex = first_mesh.positions.iter();
-        let mut mesh = Mesh {
-            vertices: Vec::with_capacity(first_mesh.positions.len() / 3),
-            normals: Vec::with_capacity(first_mesh.normals.len() / 3),
-            indices: Vec::with_capacity(first_mesh.indices.len() / 3),
-            bounds: BoundingBox::new(&Vector::new([
-                *first_vertex.next().ok_or("Empty Mesh")?,
-                *first_vertex.next().ok_or("Empty Mesh")?,
-                *first_vertex.next().ok_or("Empty Mesh")?,
-            ])),
-            model_had_normals: true,
+            // MTL libraries are relative to the OBJ file. There are none when reading from stdin.
+            |mtl_path| match base {
+                Some(base) => match reference_path(base, mtl_path, root) {
+                    Ok(path) => tobj::load_mtl(path),
+                    Err(e) => {
+                        refused.set(Some(e));
+                        Err(tobj::LoadError::OpenFileFailed)
+                    }
+                },
+                None => Ok((Vec::new(), AHashMap::new())),
+            },
+        )
+        .map_err(|e| Error::parse("OBJ", e))?;
+        if let Some(e) = refused.into_inner() {
+            return Err(e);
+        }
+        let materials: Vec<Option<Material>> = match materials {
+            Ok(materials) => materials.iter().map(obj_material).collect(),
+            Err(e) => {
+                warn!("Unable to load OBJ materials: {}", e);
+                Vec::new()
+            }
         };
+        let mut mesh = MeshBuilder::new(true);
         for model in &models {
-            let tri_idx = &model.mesh.indices;
             let p = &model.mesh.positions;
             let n = &model.mesh.normals;
-            for i in (0..tri_idx.len()).step_by(3) {
-                let index0: usize = tri_idx[i] as usize;
-                let index1: usize = tri_idx[i + 1] as usize;
-                let index2: usize = tri_idx[i + 2] as usize;
-
+            let color = model
+                .mesh
+                .material_id
+                .and_then(|id| materials.get(id).copied().flatten());
+            let position = |index: u32| {
+                let i = index as usize * 3;
+                p.get(i..i + 3)
+                    .map(|v| Vector::new([v[0], v[1], v[2]]))
+                    .ok_or_else(|| {
+                        Error::parse("OBJ", format!("Vertex index {} out of range", index))
+                    })
+            };
+            for tri_idx in model.mesh.indices.chunks_exact(3) {
                 let vertices = [
-                    Vector::new([p[index0 * 3], p[index0 * 3 + 1], p[index0 * 3 + 2]]),
-                    Vector::new([p[index1 * 3], p[index1 * 3 + 1], p[index1 * 3 + 2]]),
-                    Vector::new([p[index2 * 3], p[index2 * 3 + 1], p[index2 * 3 + 2]]),
+                    position(tri_idx[0])?,
+                    position(tri_idx[1])?,
+                    position(tri_idx[2])?,
                 ];
-                for v in vertices.iter() {
-                    mesh.bounds.expand(v);
-                    mesh.vertices.push(Vertex {
-                        position: (*v).into(),
-                    });
-                    //debug!("{:?}", v);
-                }
-
-                let normals = if !n.is_empty() {
-                    [
-                        Normal {
-                            normal: ([n[index0 * 3], n[index0 * 3 + 1], n[index0 * 3 + 2]]),
-                        },
+                // With single_index, normals are either missing or indexed the same as positions
+                let normals = if n.len() == p.len() {
+                    [tri_idx[0], tri_idx[1], tri_idx[2]].map(|i| {
+                        let i = i as usize * 3;
                         Normal {
-                            normal: ([n[index1 * 3], n[index1 * 3 + 1], n[index1 * 3 + 2]]),
-                        },
-                        Normal {
-                            normal: ([n[index2 * 3], n[index2 * 3 + 1], n[index2 * 3 + 2]]),
-                        },
-                    ]
+                            normal: [n[i], n[i + 1], n[i + 2]],
+                        }
+                    })
                 } else {
                     let n = normal(&Triangle {
                         vertices,
@@ -291,37 +483,255 @@ impl Mesh {
                     });
                     [n, n, n]
                 };
-                for normal in normals.iter() {
-                    mesh.normals.push(*normal);
+                mesh.push_tri(&vertices, normals, [color; 3]);
+            }
+        }
+        mesh.finish()
+    }
+
+    pub fn from_ply<R>(model_file: R, recalc_normals: bool) -> Result<Mesh, Error>
+    where
+        R: Read,
+    {
+        let ply = ply::read(model_file)?;
+        let use_normals = !recalc_normals && !ply.normals.is_empty();
+        let mut mesh = MeshBuilder::new(use_normals);
+
+        for tri_idx in &ply.triangles {
+            let triangle = Triangle {
+                normal: Vector::new([0.0, 0.0, 0.0]),
+                vertices: tri_idx.map(|i| Vector::new(ply.positions[i])),
+            };
+            let normals = if use_normals {
+                tri_idx.map(|i| Normal {
+                    normal: ply.normals[i],
+                })
+            } else {
+                let n = normal(&triangle);
+                [n, n, n]
+            };
+            let colors = tri_idx.map(|i| ply.colors.get(i).map(|c| Material::from_color(*c)));
+            mesh.push_tri(&triangle.vertices, normals, colors);
+        }
+
+        mesh.finish()
+    }
+
+    pub fn from_gltf<R>(
+        mut model_file: R,
+        base: Option<&Path>,
+        root: Option<&Path>,
+        recalc_normals: bool,
+    ) -> Result<Mesh, Error>
+    where
+        R: Read,
+    {
+        let mut data = Vec::new();
+        model_file.read_to_end(&mut data)?;
+        // The gltf crate trusts the length in the GLB header, so check it before parsing
+        if data.starts_with(b"glTF") {
+            let length = data
+                .get(8..12)
+                .map(|l| u32::from_le_bytes([l[0], l[1], l[2], l[3]]) as usize);
+            if !matches!(length, Some(l) if (12..=data.len()).contains(&l)) {
+                return Err(Error::parse("glTF", "Invalid GLB header"));
+            }
+        }
+        let gltf = gltf::Gltf::from_slice(&data).map_err(|e| Error::parse("glTF", e))?;
+        // The gltf crate reads relative URIs from the base and file: URIs as they are, so those
+        // are checked first. Other URIs are data or not supported.
+        if let Some(base) = base {
+            for buffer in gltf.document.buffers() {
+                let uri = match buffer.source() {
+                    gltf::buffer::Source::Uri(uri) => uri,
+                    gltf::buffer::Source::Bin => continue,
+                };
+                let path = if !uri.contains(':') {
+                    uri::percent_decode(uri)
+                        .and_then(|path| String::from_utf8(path).ok())
+                        .map(|path| base.join(path))
+                } else {
+                    uri.strip_prefix("file://")
+                        .or_else(|| uri.strip_prefix("file:"))
+                        .map(PathBuf::from)
+                };
+                if let Some(path) = path {
+                    reference_path(Path::new(""), &path, root)
+                        .map_err(|_| Error::OutsideRoot(uri.to_string()))?;
                 }
             }
         }
-        Ok(mesh)
+        // Embedded buffers, data URIs and external .bin files next to the model
+        let buffers = gltf::import_buffers(&gltf.document, base, gltf.blob)
+            .map_err(|e| Error::parse("glTF", e))?;
+
+        let scene = gltf
+            .document
+            .default_scene()
+            .or_else(|| gltf.document.scenes().next())
+            .ok_or(Error::EmptyModel)?;
+
+        let mut mesh = MeshBuilder::new(false);
+        let mut ancestors = HashSet::new();
+        for node in scene.nodes() {
+            Mesh::gltf_node(
+                &node,
+                cgmath::Matrix4::identity(),
+                &buffers,
+                recalc_normals,
+                &mut ancestors,
+                &mut mesh,
+            )?;
+        }
+
+        mesh.finish()
     }
 
-    fn process_tri(&mut self, tri: &stl_io::Triangle, recalc_normals: bool) {
-        for v in tri.vertices {
-            self.bounds.expand(&v);
-            self.vertices.push(Vertex { position: v.into() });
-            //debug!("{:?}", v);
+    // Add the meshes of a glTF node and its children, transformed into scene coordinates
+    fn gltf_node(
+        node: &gltf::Node,
+        parent: cgmath::Matrix4<f32>,
+        buffers: &[gltf::buffer::Data],
+        recalc_normals: bool,
+        ancestors: &mut HashSet<usize>,
+        result: &mut MeshBuilder,
+    ) -> Result<(), Error> {
+        if !ancestors.insert(node.index()) {
+            return Err(Error::parse("glTF", "Node hierarchy contains a cycle"));
         }
-        // Use normal from STL file if it is provided, otherwise calculate it ourselves
-        let n: Normal;
-        if recalc_normals || (tri.normal == stl_io::Vector::new([0.0, 0.0, 0.0])) {
-            self.model_had_normals = false;
-            n = normal(tri);
-        } else {
-            n = Normal {
-                normal: tri.normal.into(),
+        let transform = parent * cgmath::Matrix4::from(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            let linear = cgmath::Matrix3::from_cols(
+                transform.x.truncate(),
+                transform.y.truncate(),
+                transform.z.truncate(),
+            );
+            // Normals are transformed by the inverse transpose, so non-uniform scales don't skew them
+            let normal_matrix = linear.invert().map(|m| m.transpose()).unwrap_or(linear);
+            // Mirroring transforms turn the triangles inside out
+            let flip = linear.determinant() < 0.0;
+
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    warn!("Skipping glTF primitive that is not a triangle list");
+                    continue;
+                }
+                let reader = primitive.reader(|b| buffers.get(b.index()).map(|d| &d.0[..]));
+                let positions: Vec<[f32; 3]> = match reader.read_positions() {
+                    Some(p) => p.collect(),
+                    None => continue,
+                };
+                let normals: Vec<[f32; 3]> = match reader.read_normals() {
+                    Some(n) if !recalc_normals => n.collect(),
+                    _ => Vec::new(),
+                };
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(i) => i.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                // Models without a material use the configured one
+                let color = primitive.material().index().map(|_| {
+                    let [r, g, b, _] = primitive
+                        .material()
+                        .pbr_metallic_roughness()
+                        .base_color_factor();
+                    Material::from_color([r, g, b].map(linear_to_srgb))
+                });
+
+                for tri_idx in indices.chunks_exact(3) {
+                    let mut tri_idx = [tri_idx[0], tri_idx[1], tri_idx[2]].map(|i| i as usize);
+                    if flip {
+                        tri_idx.swap(1, 2);
+                    }
+                    let mut vertices = [Vector::new([0.0; 3]); 3];
+                    for (v, &i) in vertices.iter_mut().zip(tri_idx.iter()) {
+                        let p = positions.get(i).ok_or_else(|| {
+                            Error::parse("glTF", format!("Vertex index {} out of range", i))
+                        })?;
+                        let p = transform * cgmath::Vector3::from(*p).extend(1.0);
+                        *v = Vector::new([p.x / p.w, p.y / p.w, p.z / p.w]);
+                    }
+                    let triangle = Triangle {
+                        normal: Vector::new([0.0, 0.0, 0.0]),
+                        vertices,
+                    };
+                    let normals = if normals.len() == positions.len() {
+                        tri_idx.map(|i| {
+                            let n = normal_matrix * cgmath::Vector3::from(normals[i]);
+                            Normal { normal: n.into() }
+                        })
+                    } else {
+                        let n = normal(&triangle);
+                        [n, n, n]
+                    };
+                    result.push_tri(&triangle.vertices, normals, [color; 3]);
+                }
+            }
+        }
+
+        for child in node.children() {
+            Mesh::gltf_node(
+                &child,
+                transform,
+                buffers,
+                recalc_normals,
+                ancestors,
+                result,
+            )?;
+        }
+        ancestors.remove(&node.index());
+        Ok(())
+    }
+
+    /// Vertex indices of each triangle.
+    pub fn triangles(&self) -> impl Iterator<Item = [usize; 3]> + '_ {
+        self.indices
+            .chunks_exact(3)
+            .map(|t| [t[0] as usize, t[1] as usize, t[2] as usize])
+    }
+
+    /// Simplifies the mesh to at most `max_triangles` triangles, by merging vertices that are
+    /// close together. The simplified mesh has flat normals.
+    pub fn decimate(&mut self, max_triangles: usize) {
+        let clustered = match decimate::decimate(self, max_triangles) {
+            Some(c) => c,
+            None => return,
+        };
+        let mut mesh = MeshBuilder::new(false);
+        for (cells, corners) in clustered.triangles {
+            let triangle = Triangle {
+                normal: Vector::new([0.0, 0.0, 0.0]),
+                vertices: cells.map(|c| Vector::new(clustered.positions[c as usize])),
             };
+            let n = normal(&triangle);
+            let colors = corners.map(|v| self.colors.get(v).copied().flatten());
+            mesh.push_tri(&triangle.vertices, [n, n, n], colors);
         }
-        //debug!("{:?}",tri.normal);
-        // TODO: Figure out how to get away with 1 normal instead of 3
-        for _ in 0..3 {
-            self.normals.push(n);
+        match mesh.finish() {
+            Ok(mesh) => *self = mesh,
+            Err(_) => warn!("Nothing is left of the model after decimation. Using all triangles."),
         }
     }
 
+    /// Replaces the normals with ones averaged over the faces around each vertex, so curved
+    /// surfaces look smooth. Faces meeting at more than `crease_angle` degrees keep a sharp edge.
+    ///
+    /// Vertices at the same position are welded, so the faces around them can share them.
+    pub fn smooth_normals(&mut self, crease_angle: f32) {
+        let smoothed = smooth::smooth(self, crease_angle);
+        info!(
+            "Smoothed {} vertices into {}",
+            self.vertices.len(),
+            smoothed.vertices.len()
+        );
+        self.vertices = smoothed.vertices;
+        self.normals = smoothed.normals;
+        self.colors = smoothed.colors;
+        self.indices = smoothed.indices;
+        self.model_had_normals = false;
+    }
+
     // Move the mesh to be centered at the origin
     // and scaled to fit a 2 x 2 x 2 box. This means that
     // all coordinates will be between -1.0 and 1.0
@@ -343,6 +753,45 @@ impl Mesh {
     }
 }
 
+// Phong colors of an MTL material. Materials without a diffuse color use the configured material.
+fn obj_material(material: &tobj::Material) -> Option<Material> {
+    let diffuse = material.diffuse?;
+    Some(Material {
+        // Ka is a reflectance like Kd, so it is lit by the same ambient light as Material::from_color
+        ambient: material
+            .ambient
+            .unwrap_or(diffuse)
+            .map(|c| c * AMBIENT_LIGHT),
+        diffuse,
+        specular: material.specular.unwrap_or([1.0, 1.0, 1.0]),
+    })
+}
+
+// Path of a file the model references, relative to `base` unless it's absolute. Symlinks are
+// resolved before checking that it's in `root`, so they can't lead out of it. Files that can't be
+// found are left for the loader to report.
+fn reference_path(base: &Path, reference: &Path, root: Option<&Path>) -> Result<PathBuf, Error> {
+    let path = base.join(reference);
+    if let Some(root) = root {
+        match fs::canonicalize(&path) {
+            Ok(resolved) if !resolved.starts_with(root) => {
+                return Err(Error::OutsideRoot(reference.display().to_string()))
+            }
+            _ => (),
+        }
+    }
+    Ok(path)
+}
+
+// glTF colors are linear, the shaders work in sRGB like the other formats
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 impl fmt::Display for Mesh {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "Verts: {}", self.vertices.len())?;
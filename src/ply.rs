@@ -0,0 +1,443 @@
+// PLY Reader
+// ==========
+//
+// Reads the vertex and face elements of a PLY (Polygon File Format) file.
+// Supports the ascii, binary_little_endian and binary_big_endian encodings.
+// Properties may appear in any order and unknown elements and properties are skipped.
+
+use crate::error::Error;
+use std::io::Read;
+use std::str::SplitAsciiWhitespace;
+
+/// Geometry read from a PLY file. Faces are already triangulated.
+pub struct Ply {
+    pub positions: Vec<[f32; 3]>,
+    // Either empty or one entry per vertex
+    pub normals: Vec<[f32; 3]>,
+    // Either empty or one entry per vertex, as 0.0 to 1.0
+    pub colors: Vec<[f32; 3]>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Encoding {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, Error> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(invalid(format!("Unknown property type {}", name))),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, Scalar::F32 | Scalar::F64)
+    }
+}
+
+enum PropertyType {
+    Scalar(Scalar),
+    // Count type and item type
+    List(Scalar, Scalar),
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+fn invalid<E: ToString>(message: E) -> Error {
+    Error::parse("PLY", message.to_string())
+}
+
+// Source of property values, either whitespace separated text or packed binary data
+enum Values<'a> {
+    Ascii(SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl<'a> Values<'a> {
+    fn next(&mut self, ty: Scalar) -> Result<f64, Error> {
+        match self {
+            Values::Ascii(words) => {
+                let word = words
+                    .next()
+                    .ok_or_else(|| invalid("Unexpected end of file"))?;
+                word.parse::<f64>()
+                    .map_err(|_| invalid(format!("Invalid number {:?}", word)))
+            }
+            Values::Binary { data, big_endian } => {
+                if data.len() < ty.size() {
+                    return Err(invalid("Unexpected end of file"));
+                }
+                let (bytes, rest) = data.split_at(ty.size());
+                *data = rest;
+                let mut buf = [0u8; 8];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                if *big_endian {
+                    buf[..bytes.len()].reverse();
+                }
+                Ok(match ty {
+                    Scalar::I8 => buf[0] as i8 as f64,
+                    Scalar::U8 => buf[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(buf),
+                })
+            }
+        }
+    }
+
+    // Read one value of every property of an element.
+    // Scalar properties end up in `scalars`, only the last list is kept.
+    fn next_row(
+        &mut self,
+        element: &Element,
+        scalars: &mut Vec<f64>,
+        list: &mut Vec<f64>,
+    ) -> Result<(), Error> {
+        scalars.clear();
+        for property in &element.properties {
+            match property.ty {
+                PropertyType::Scalar(ty) => scalars.push(self.next(ty)?),
+                PropertyType::List(count_ty, item_ty) => {
+                    let count = self.next(count_ty)?;
+                    if count < 0.0 {
+                        return Err(invalid("Negative list length"));
+                    }
+                    list.clear();
+                    for _ in 0..count as usize {
+                        list.push(self.next(item_ty)?);
+                    }
+                    // Keep the scalar indices lined up with the properties
+                    scalars.push(0.0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn read<R: Read>(mut model_file: R) -> Result<Ply, Error> {
+    let mut data = Vec::new();
+    model_file.read_to_end(&mut data)?;
+
+    // Header
+    // ------
+
+    let header_end = find_header_end(&data).ok_or_else(|| invalid("Missing end_header"))?;
+    let header = std::str::from_utf8(&data[..header_end.0])
+        .map_err(|_| invalid("Header is not valid text"))?;
+    let body = &data[header_end.1..];
+
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return Err(invalid("Missing ply magic number"));
+    }
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["format", format, _version] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::BinaryLittleEndian,
+                    "binary_big_endian" => Encoding::BinaryBigEndian,
+                    _ => return Err(invalid(format!("Unknown format {}", format))),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid(format!("Invalid element count {:?}", count)))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_ty, item_ty, name] => {
+                let ty = PropertyType::List(Scalar::parse(count_ty)?, Scalar::parse(item_ty)?);
+                add_property(&mut elements, name, ty)?;
+            }
+            ["property", ty, name] => {
+                let ty = PropertyType::Scalar(Scalar::parse(ty)?);
+                add_property(&mut elements, name, ty)?;
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => (),
+            _ => return Err(invalid(format!("Unexpected header line {:?}", line))),
+        }
+    }
+    let encoding = encoding.ok_or_else(|| invalid("Missing format"))?;
+
+    // Body
+    // ----
+
+    let mut values = match encoding {
+        Encoding::Ascii => Values::Ascii(
+            std::str::from_utf8(body)
+                .map_err(|_| invalid("Body is not valid text"))?
+                .split_ascii_whitespace(),
+        ),
+        _ => Values::Binary {
+            data: body,
+            big_endian: encoding == Encoding::BinaryBigEndian,
+        },
+    };
+
+    let mut ply = Ply {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        colors: Vec::new(),
+        triangles: Vec::new(),
+    };
+    let vertex_count = elements
+        .iter()
+        .find(|e| e.name == "vertex")
+        .map(|e| e.count)
+        .unwrap_or(0);
+    let mut scalars = Vec::new();
+    let mut list = Vec::new();
+
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let position = [&["x"], &["y"], &["z"]].map(|n| element.find(n));
+                let [Some(x), Some(y), Some(z)] = position else {
+                    return Err(invalid("Vertices have no x, y and z properties"));
+                };
+                let normal = match [&["nx"], &["ny"], &["nz"]].map(|n| element.find(n)) {
+                    [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+                    _ => None,
+                };
+                let color = match [
+                    &["red", "diffuse_red"],
+                    &["green", "diffuse_green"],
+                    &["blue", "diffuse_blue"],
+                ]
+                .map(|n| element.find(n))
+                {
+                    [Some(r), Some(g), Some(b)] => Some([r, g, b]),
+                    _ => None,
+                };
+                // Integer colors go from 0 to the maximum of their type, floats from 0 to 1
+                let color_scale = color.map(|c| {
+                    c.map(|i| match element.properties[i].ty {
+                        PropertyType::Scalar(ty) if ty.is_float() => 1.0,
+                        PropertyType::Scalar(Scalar::U16) => 1.0 / u16::MAX as f64,
+                        _ => 1.0 / u8::MAX as f64,
+                    })
+                });
+
+                for _ in 0..element.count {
+                    values.next_row(element, &mut scalars, &mut list)?;
+                    ply.positions.push([x, y, z].map(|i| scalars[i] as f32));
+                    if let Some(n) = normal {
+                        ply.normals.push(n.map(|i| scalars[i] as f32));
+                    }
+                    if let (Some(c), Some(scale)) = (color, color_scale) {
+                        ply.colors
+                            .push([0, 1, 2].map(|j| (scalars[c[j]] * scale[j]) as f32));
+                    }
+                }
+            }
+            "face" => {
+                let indices = element
+                    .find(&["vertex_indices", "vertex_index"])
+                    .ok_or_else(|| invalid("Faces have no vertex_indices property"))?;
+                if !matches!(element.properties[indices].ty, PropertyType::List(..)) {
+                    return Err(invalid("vertex_indices is not a list"));
+                }
+                // Only one list per face is supported, so it must be the vertex indices
+                if element
+                    .properties
+                    .iter()
+                    .filter(|p| matches!(p.ty, PropertyType::List(..)))
+                    .count()
+                    > 1
+                {
+                    return Err(invalid("Faces with more than one list property"));
+                }
+
+                for _ in 0..element.count {
+                    values.next_row(element, &mut scalars, &mut list)?;
+                    let mut face = Vec::with_capacity(list.len());
+                    for &i in &list {
+                        if i < 0.0 || i as usize >= vertex_count {
+                            return Err(invalid(format!("Vertex index {} out of range", i)));
+                        }
+                        face.push(i as usize);
+                    }
+                    // Polygons are split into a fan of triangles
+                    for i in 2..face.len() {
+                        ply.triangles.push([face[0], face[i - 1], face[i]]);
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    values.next_row(element, &mut scalars, &mut list)?;
+                }
+            }
+        }
+    }
+
+    Ok(ply)
+}
+
+fn add_property(elements: &mut [Element], name: &str, ty: PropertyType) -> Result<(), Error> {
+    let element = elements
+        .last_mut()
+        .ok_or_else(|| invalid("Property before any element"))?;
+    element.properties.push(Property {
+        name: name.to_string(),
+        ty,
+    });
+    Ok(())
+}
+
+// Returns the end of the header text and the start of the body. The header ends with a line of
+// its own, so end_header in a comment doesn't count.
+fn find_header_end(data: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    loop {
+        let line_end = data[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|i| start + i);
+        let line = &data[start..line_end.unwrap_or(data.len())];
+        if line.strip_suffix(b"\r").unwrap_or(line) == b"end_header" {
+            // The body starts after the line break, which may be \r\n
+            return Some((start, line_end.map_or(data.len(), |i| i + 1)));
+        }
+        start = line_end? + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "ply
+format ascii 1.0
+comment made by hand
+element vertex 4
+property uchar red
+property float x
+property float y
+property float z
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+255 0 0 0 0 0
+255 1 0 0 0 0
+255 1 1 0 0 0
+255 0 1 0 0 0
+4 0 1 2 3
+";
+
+    #[test]
+    fn ascii_quad() {
+        let ply = read(ASCII.as_bytes()).unwrap();
+        assert_eq!(4, ply.positions.len());
+        assert_eq!([1.0, 1.0, 0.0], ply.positions[2]);
+        assert_eq!([1.0, 0.0, 0.0], ply.colors[0]);
+        assert!(ply.normals.is_empty());
+        assert_eq!(vec![[0, 1, 2], [0, 2, 3]], ply.triangles);
+    }
+
+    #[test]
+    fn binary_big_endian() {
+        let mut data = b"ply\r
+format binary_big_endian 1.0\r
+element vertex 3\r
+property double z\r
+property float x\r
+property float y\r
+element face 1\r
+property list uchar uint vertex_indices\r
+end_header\r
+"
+        .to_vec();
+        for (x, y) in [(0.0f32, 0.0f32), (2.0, 0.0), (0.0, 2.0)] {
+            data.extend_from_slice(&1.5f64.to_be_bytes());
+            data.extend_from_slice(&x.to_be_bytes());
+            data.extend_from_slice(&y.to_be_bytes());
+        }
+        data.push(3);
+        for i in 0u32..3 {
+            data.extend_from_slice(&i.to_be_bytes());
+        }
+
+        let ply = read(&data[..]).unwrap();
+        assert_eq!(
+            vec![[0.0, 0.0, 1.5], [2.0, 0.0, 1.5], [0.0, 2.0, 1.5]],
+            ply.positions
+        );
+        assert!(ply.colors.is_empty());
+        assert_eq!(vec![[0, 1, 2]], ply.triangles);
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        let ply = ASCII.replace("4 0 1 2 3", "3 0 1 4");
+        assert!(matches!(read(ply.as_bytes()), Err(Error::Parse { .. })));
+    }
+
+    #[test]
+    fn end_header_in_comment() {
+        let ply = ASCII.replace("comment made by hand", "comment end_header");
+        assert_eq!(4, read(ply.as_bytes()).unwrap().positions.len());
+        assert_eq!(Some((5, 17)), find_header_end(b"ply\r\nend_header\r\n\x00"));
+    }
+}
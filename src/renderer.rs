@@ -0,0 +1,945 @@
+use crate::config::{
+    AAMethod, Backend, Camera, Config, Material, Projection, RenderOptions, Sheet, UpAxis,
+    SHADOW_OPACITY,
+};
+use crate::error::Error;
+use crate::floor::{self, FloorVertex};
+use crate::fxaa;
+use crate::mesh::{Mesh, Normal, Vertex};
+use crate::sheet;
+use crate::software;
+use cgmath::{EuclideanSpace, InnerSpace, SquareMatrix, Transform};
+use glium::backend::Facade;
+use glium::glutin::dpi::PhysicalSize;
+use glium::glutin::event_loop::{EventLoop, EventLoopBuilder};
+use glium::{glutin, CapabilitiesSource, Surface};
+use std::panic;
+use std::rc::Rc;
+use std::slice;
+
+// Direction of light source
+//const LIGHT_DIR: [f32; 3] = [-1.4, 0.4, -0.7];
+pub(crate) const LIGHT_DIR: [f32; 3] = [-1.1, 0.4, 1.0];
+
+fn print_matrix(m: [[f32; 4]; 4]) {
+    for row in &m {
+        debug!("{:.3}\t{:.3}\t{:.3}\t{:.3}", row[0], row[1], row[2], row[3]);
+    }
+    debug!("");
+}
+
+fn print_context_info(display: &glium::backend::Context) {
+    // Print context information
+    info!("GL Version:   {:?}", display.get_opengl_version());
+    info!("GL Version:   {}", display.get_opengl_version_string());
+    info!("GLSL Version: {:?}", display.get_supported_glsl_version());
+    info!("Vendor:       {}", display.get_opengl_vendor_string());
+    info!("Renderer      {}", display.get_opengl_renderer_string());
+    info!("Free GPU Mem: {:?}", display.get_free_video_memory());
+    info!(
+        "Depth Bits:   {:?}\n",
+        display.get_capabilities().depth_bits
+    );
+}
+
+pub(crate) fn create_normal_display(
+    config: &Config,
+) -> Result<(glium::Display, EventLoop<()>), Error> {
+    // Creating the event loop panics instead of returning an error if there is no display server
+    let event_loop = panic::catch_unwind(EventLoop::new)
+        .map_err(|_| Error::Context("Unable to create event loop".to_string()))?;
+    let window_dim = PhysicalSize::new(config.width, config.height);
+    let window = glutin::window::WindowBuilder::new()
+        .with_title("stl-thumb")
+        .with_inner_size(window_dim)
+        .with_min_inner_size(window_dim)
+        .with_max_inner_size(window_dim)
+        .with_visible(config.visible);
+    let cb = glutin::ContextBuilder::new().with_depth_buffer(24);
+    //.with_multisampling(8);
+    //.with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGlEs, (2, 0)));
+    let display = glium::Display::new(window, cb, &event_loop)?;
+    print_context_info(&display);
+    Ok((display, event_loop))
+}
+
+#[cfg(target_os = "windows")]
+fn create_headless_display(config: &Config) -> Result<glium::HeadlessRenderer, Error> {
+    use glium::glutin::platform::windows::EventLoopBuilderExtWindows;
+
+    let event_loop: EventLoop<()> = EventLoopBuilder::new().with_any_thread(true).build();
+    let size = PhysicalSize::new(config.width, config.height);
+    let cb = glutin::ContextBuilder::new();
+    let context = cb.build_headless(&event_loop, size)?;
+
+    let context = unsafe { context.treat_as_current() };
+    let display = glium::backend::glutin::headless::Headless::new(context)?;
+    print_context_info(&display);
+    Ok(display)
+}
+
+#[cfg(target_os = "linux")]
+fn create_headless_display(config: &Config) -> Result<glium::HeadlessRenderer, Error> {
+    use glium::glutin::platform::unix::{EventLoopBuilderExtUnix, HeadlessContextExt};
+
+    let size = PhysicalSize::new(config.width, config.height);
+    let cb = glutin::ContextBuilder::new();
+    let context: glium::glutin::Context<glium::glutin::NotCurrent>;
+
+    // Linux requires an elaborate chain of attempts and fallbacks to find the ideal type of opengl context.
+
+    // If there is no X server or Wayland, creating the event loop will fail first.
+    // If this happens we catch the panic and fall back to osmesa software rendering, which doesn't require an event loop.
+    // TODO: Submit PR upstream to stop panicing
+    let event_loop_result: Result<EventLoop<()>, _> =
+        panic::catch_unwind(|| EventLoopBuilder::new().with_any_thread(true).build());
+
+    match event_loop_result {
+        Ok(event_loop) => {
+            context = {
+                // Try surfaceless, headless, and osmesa in that order
+                // This is the procedure recommended in
+                // https://github.com/rust-windowing/glutin/blob/bab33a84dfb094ff65c059400bed7993434638e2/glutin_examples/examples/headless.rs
+                match cb.clone().build_surfaceless(&event_loop) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("Unable to create surfaceless GL context. Trying headless instead. Reason: {:?}", e);
+                        match cb.clone().build_headless(&event_loop, size) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                warn!("Unable to create headless GL context. Trying osmesa software renderer instead. Reason: {:?}", e);
+                                cb.build_osmesa(size)?
+                            }
+                        }
+                    }
+                }
+            };
+        }
+        Err(e) => {
+            warn!(
+                "No Wayland or X server. Falling back to osmesa software rendering. Reason {:?}",
+                e
+            );
+            context = cb.build_osmesa(size)?;
+        }
+    };
+
+    let context = unsafe { context.treat_as_current() };
+    let display = glium::backend::glutin::headless::Headless::new(context)?;
+    print_context_info(&display);
+    Ok(display)
+}
+
+// Everything that has to fit in the image: the model, its shadow and the floor
+fn framed_points(
+    mesh: &Mesh,
+    shadow: Option<&cgmath::Matrix4<f32>>,
+    floor: &[FloorVertex],
+) -> Vec<cgmath::Point3<f32>> {
+    let model = mesh
+        .vertices
+        .iter()
+        .map(|v| cgmath::Point3::from(v.position));
+    let mut points: Vec<_> = model.clone().collect();
+    if let Some(shadow) = shadow {
+        points.extend(model.map(|p| shadow.transform_point(p)));
+    }
+    points.extend(floor.iter().map(|v| cgmath::Point3::from(v.position)));
+    points
+}
+
+// Bounding box of the points in view space
+fn view_bounds<I>(
+    modelview: &cgmath::Matrix4<f32>,
+    points: I,
+) -> (cgmath::Point3<f32>, cgmath::Point3<f32>)
+where
+    I: IntoIterator<Item = cgmath::Point3<f32>>,
+{
+    let mut min = cgmath::Point3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = cgmath::Point3::new(f32::MIN, f32::MIN, f32::MIN);
+    for point in points {
+        let p = modelview.transform_point(point);
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    (min, max)
+}
+
+// Finds where the camera has to move in view space so every point is inside a frustum with
+// the given tangents of its half angles. Also returns near and far planes enclosing the points.
+//
+// A vertex is inside when |x - cx| <= tan_x * (cz - z), which means
+// x + tan_x * z <= cx + tan_x * cz and x - tan_x * z >= cx - tan_x * cz.
+// The tightest fit makes both of these equalities for the outermost vertices.
+fn fit_perspective<I>(
+    modelview: &cgmath::Matrix4<f32>,
+    points: I,
+    tan_x: f32,
+    tan_y: f32,
+) -> (cgmath::Vector3<f32>, f32, f32)
+where
+    I: IntoIterator<Item = cgmath::Point3<f32>>,
+{
+    let mut upper = [f32::MIN; 2];
+    let mut lower = [f32::MAX; 2];
+    let mut z_range = (f32::MAX, f32::MIN);
+    for point in points {
+        let p = modelview.transform_point(point);
+        for (i, tan) in [tan_x, tan_y].into_iter().enumerate() {
+            upper[i] = upper[i].max(p[i] + tan * p.z);
+            lower[i] = lower[i].min(p[i] - tan * p.z);
+        }
+        z_range = (z_range.0.min(p.z), z_range.1.max(p.z));
+    }
+
+    let center = [0, 1].map(|i| (upper[i] + lower[i]) / 2.0);
+    let cz = ((upper[0] - lower[0]) / (2.0 * tan_x)).max((upper[1] - lower[1]) / (2.0 * tan_y));
+    // Depth of the nearest and farthest vertex from the new camera position
+    let nearest = cz - z_range.1;
+    let farthest = cz - z_range.0;
+    (
+        cgmath::Vector3::new(center[0], center[1], cz),
+        (nearest * 0.5).max(0.001),
+        farthest * 2.0,
+    )
+}
+
+// Camera on a sphere around the origin, with Z up.
+// With a distance of 0 the camera sits at the origin, and framing moves it back later.
+fn camera_view(camera: &Camera) -> cgmath::Matrix4<f32> {
+    let (sin_az, cos_az) = camera.azimuth.to_radians().sin_cos();
+    let (sin_el, cos_el) = camera.elevation.to_radians().sin_cos();
+    let direction = cgmath::Vector3::new(sin_az * cos_el, -cos_az * cos_el, sin_el);
+    let position = cgmath::Point3::from_vec(direction * camera.distance);
+    // Tangent to the sphere towards the top, so looking straight up or down still works
+    let up = cgmath::Vector3::new(-sin_az * sin_el, cos_az * sin_el, cos_el);
+    let look_at = cgmath::Matrix4::look_to_rh(position, -direction, up);
+    cgmath::Matrix4::from_angle_z(cgmath::Deg(camera.roll)) * look_at
+}
+
+// Transformation matrix (positions, scales and rotates model)
+fn model_transform(camera: &Camera, mesh: &Mesh) -> cgmath::Matrix4<f32> {
+    match camera.up {
+        UpAxis::Z => mesh.scale_and_center(),
+        // Turn Y up into Z up, which keeps the front of the model (+Z) facing -Y
+        UpAxis::Y => cgmath::Matrix4::from_angle_x(cgmath::Deg(90.0)) * mesh.scale_and_center(),
+    }
+}
+
+// The shadow and floor are on the ground under the model, which can only be seen from above
+fn sees_ground(camera: &Camera) -> bool {
+    camera.elevation > 0.0
+}
+
+// Flattens the model onto the ground plane under it along the light direction, in model
+// coordinates. None if shadows are disabled or the camera doesn't look down on the ground.
+pub(crate) fn shadow_matrix(options: &RenderOptions, mesh: &Mesh) -> Option<cgmath::Matrix4<f32>> {
+    let camera = &options.camera;
+    if !options.shadow || !sees_ground(camera) {
+        return None;
+    }
+    let (up, ground) = match camera.up {
+        UpAxis::Z => (cgmath::Vector3::unit_z(), mesh.bounds.min.z),
+        UpAxis::Y => (cgmath::Vector3::unit_y(), mesh.bounds.min.y),
+    };
+
+    // The light is fixed relative to the camera, so turn it back into model coordinates
+    let modelview = camera_view(camera) * model_transform(camera, mesh);
+    let rotation = cgmath::Matrix3::from_cols(
+        modelview.x.truncate(),
+        modelview.y.truncate(),
+        modelview.z.truncate(),
+    );
+    let light = rotation.invert()? * cgmath::Vector3::from(LIGHT_DIR);
+    let height = up.dot(light);
+    if height <= 0.0 {
+        return None;
+    }
+
+    // p - light * (up . p - ground) / (up . light)
+    let d = light / height;
+    Some(cgmath::Matrix4::from_cols(
+        (cgmath::Vector3::unit_x() - d * up.x).extend(0.0),
+        (cgmath::Vector3::unit_y() - d * up.y).extend(0.0),
+        (cgmath::Vector3::unit_z() - d * up.z).extend(0.0),
+        (d * ground).extend(1.0),
+    ))
+}
+
+// Grid under the model in model coordinates, empty if there is none or it can't be seen
+pub(crate) fn floor_triangles(options: &RenderOptions, mesh: &Mesh) -> Vec<FloorVertex> {
+    match options.grid {
+        Some(ref grid) if sees_ground(&options.camera) => {
+            floor::triangles(grid, options.camera.up, &mesh.bounds)
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Color of the shadow drawn over the background, which is what ends up in the image
+pub(crate) fn shadow_color(background: (f32, f32, f32, f32)) -> [f32; 4] {
+    let (r, g, b, a) = background;
+    let alpha = SHADOW_OPACITY + a * (1.0 - SHADOW_OPACITY);
+    let scale = a * (1.0 - SHADOW_OPACITY) / alpha;
+    [r * scale, g * scale, b * scale, alpha]
+}
+
+// Model-view and perspective matrices.
+// Shared by all backends so they frame the model identically.
+pub(crate) fn scene_matrices(
+    options: &RenderOptions,
+    mesh: &Mesh,
+) -> (cgmath::Matrix4<f32>, cgmath::Matrix4<f32>) {
+    let (modelviews, perspective_matrix) = view_matrices(slice::from_ref(options), mesh);
+    (modelviews[0], perspective_matrix)
+}
+
+// Model-view matrix of each view, and a single projection that fits the model in all of them
+// so it keeps its size from one frame of an animation to the next. The projection settings
+// come from the first view.
+pub(crate) fn view_matrices(
+    views: &[RenderOptions],
+    mesh: &Mesh,
+) -> (Vec<cgmath::Matrix4<f32>>, cgmath::Matrix4<f32>) {
+    let options = &views[0];
+    let camera = &options.camera;
+
+    // View matrix (convert to positions relative to camera)
+    debug!("View:");
+    print_matrix(camera_view(camera).into());
+
+    let mut modelviews: Vec<_> = views
+        .iter()
+        .map(|view| camera_view(&view.camera) * model_transform(&view.camera, mesh))
+        .collect();
+    // Everything that has to fit in any of the views, in view space. Only one view is
+    // collected at a time, large models have a lot of points.
+    let points = || {
+        views.iter().zip(&modelviews).flat_map(|(view, modelview)| {
+            framed_points(
+                mesh,
+                shadow_matrix(view, mesh).as_ref(),
+                &floor_triangles(view, mesh),
+            )
+            .into_iter()
+            .map(move |p| modelview.transform_point(p))
+        })
+    };
+    let identity = cgmath::Matrix4::identity();
+    let aspect = options.width as f32 / options.height as f32;
+    // Share of the image the model may fill
+    let fill = (1.0 - camera.padding / 50.0).max(0.01);
+
+    let perspective_matrix = match camera.projection {
+        // Perspective matrix (give illusion of depth)
+        Projection::Perspective if camera.distance > 0.0 => {
+            cgmath::perspective(cgmath::Deg(camera.fov), aspect, 0.1, 1024.0)
+        }
+        // Move the camera as close as it can get with the whole model in view
+        Projection::Perspective => {
+            let tan_y = (camera.fov.to_radians() / 2.0).tan() * fill;
+            let (offset, near, far) = fit_perspective(&identity, points(), tan_y * aspect, tan_y);
+            for modelview in &mut modelviews {
+                *modelview = cgmath::Matrix4::from_translation(-offset) * *modelview;
+            }
+            cgmath::perspective(cgmath::Deg(camera.fov), aspect, near, far)
+        }
+        // View volume fitted around the model
+        Projection::Orthographic => {
+            let (min, max) = view_bounds(&identity, points());
+            let center = (min + max.to_vec()) / 2.0;
+            let half_height = ((max.y - min.y) / 2.0).max((max.x - min.x) / 2.0 / aspect) / fill;
+            let half_width = half_height * aspect;
+            // The camera looks down -Z, so the nearest point has the largest z
+            cgmath::ortho(
+                center.x - half_width,
+                center.x + half_width,
+                center.y - half_height,
+                center.y + half_height,
+                -max.z - 0.1,
+                -min.z + 0.1,
+            )
+        }
+    };
+    debug!("Projection:");
+    print_matrix(perspective_matrix.into());
+
+    (modelviews, perspective_matrix)
+}
+
+// Compiled shaders and the FXAA system only depend on the GL context,
+// so they are created once and reused for every render with that context.
+pub(crate) struct Pipeline {
+    program: glium::Program,
+    shadow_program: glium::Program,
+    floor_program: glium::Program,
+    fxaa: fxaa::FxaaSystem,
+}
+
+fn compile_program<F>(display: &F, vertex: &str, pixel: &str) -> Result<glium::Program, Error>
+where
+    F: Facade,
+{
+    // TODO: Cache program binary
+    match glium::Program::from_source(display, vertex, pixel, None) {
+        Ok(p) => Ok(p),
+        Err(glium::CompilationError(err, _)) => Err(Error::Shader(err)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl Pipeline {
+    pub(crate) fn new<F>(display: &F) -> Result<Pipeline, Error>
+    where
+        F: Facade,
+    {
+        // Load and compile shaders
+        // ------------------------
+
+        let program = compile_program(
+            display,
+            include_str!("shaders/model.vert"),
+            include_str!("shaders/model.frag"),
+        )?;
+        let shadow_program = compile_program(
+            display,
+            include_str!("shaders/shadow.vert"),
+            include_str!("shaders/shadow.frag"),
+        )?;
+        let floor_program = compile_program(
+            display,
+            include_str!("shaders/floor.vert"),
+            include_str!("shaders/floor.frag"),
+        )?;
+
+        // Create FXAA system
+        let fxaa = fxaa::FxaaSystem::new(display)?;
+
+        Ok(Pipeline {
+            program,
+            shadow_program,
+            floor_program,
+            fxaa,
+        })
+    }
+}
+
+// Material of every vertex, using the configured one where the model has no colors
+pub(crate) fn vertex_colors(options: &RenderOptions, mesh: &Mesh) -> Vec<Material> {
+    if mesh.colors.is_empty() {
+        vec![options.material; mesh.vertices.len()]
+    } else {
+        mesh.colors
+            .iter()
+            .map(|c| c.unwrap_or(options.material))
+            .collect()
+    }
+}
+
+// Mesh data in GPU memory. Uploaded once, then drawn for any number of views that share
+// the material, the grid and the up axis.
+pub(crate) struct MeshBuffers {
+    vertices: glium::VertexBuffer<Vertex>,
+    normals: glium::VertexBuffer<Normal>,
+    colors: glium::VertexBuffer<Material>,
+    indices: glium::IndexBuffer<u32>,
+    floor: glium::VertexBuffer<FloorVertex>,
+}
+
+impl MeshBuffers {
+    pub(crate) fn new<F>(
+        display: &F,
+        options: &RenderOptions,
+        mesh: &Mesh,
+    ) -> Result<MeshBuffers, Error>
+    where
+        F: Facade,
+    {
+        let triangles = glium::index::PrimitiveType::TrianglesList;
+        // Views that can't see the ground skip the floor when drawing
+        let floor = match options.grid {
+            Some(ref grid) => floor::triangles(grid, options.camera.up, &mesh.bounds),
+            None => Vec::new(),
+        };
+        Ok(MeshBuffers {
+            vertices: glium::VertexBuffer::new(display, &mesh.vertices)?,
+            normals: glium::VertexBuffer::new(display, &mesh.normals)?,
+            colors: glium::VertexBuffer::new(display, &vertex_colors(options, mesh))?,
+            indices: glium::IndexBuffer::new(display, triangles, &mesh.indices)?,
+            floor: glium::VertexBuffer::new(display, &floor)?,
+        })
+    }
+}
+
+pub(crate) fn render_pipeline(
+    pipeline: &Pipeline,
+    options: &RenderOptions,
+    mesh: &Mesh,
+    buffers: &MeshBuffers,
+    (modelview_matrix, perspective_matrix): (cgmath::Matrix4<f32>, cgmath::Matrix4<f32>),
+    framebuffer: &mut glium::framebuffer::SimpleFrameBuffer,
+    texture: &glium::Texture2d,
+) -> Result<image::DynamicImage, Error> {
+    // Graphics Stuff
+    // ==============
+
+    let params = glium::DrawParameters {
+        depth: glium::Depth {
+            test: glium::draw_parameters::DepthTest::IfLess,
+            write: true,
+            ..Default::default()
+        },
+        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+        ..Default::default()
+    };
+
+    // Setup uniforms
+    // --------------
+
+    let uniforms = uniform! {
+        //model: Into::<[[f32; 4]; 4]>::into(transform_matrix),
+        //view: Into::<[[f32; 4]; 4]>::into(view_matrix),
+        modelview: Into::<[[f32; 4]; 4]>::into(modelview_matrix),
+        perspective: Into::<[[f32; 4]; 4]>::into(perspective_matrix),
+        u_light: LIGHT_DIR,
+    };
+
+    // The shadow lies flat on the ground, under everything else. It is drawn first in a single
+    // color without blending, so overlapping triangles don't make it darker. It also sets the
+    // depth to 0, which marks the shadowed pixels for the floor.
+    let shadow_uniforms = shadow_matrix(options, mesh).map(|shadow| {
+        uniform! {
+            matrix: Into::<[[f32; 4]; 4]>::into(perspective_matrix * modelview_matrix * shadow),
+            u_color: shadow_color(options.background),
+        }
+    });
+    let shadow_params = glium::DrawParameters {
+        depth: glium::Depth {
+            test: glium::draw_parameters::DepthTest::Overwrite,
+            write: true,
+            range: (0.0, 0.0),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // The floor is drawn lit where the depth is still cleared, and darkened where the shadow is
+    let floor_matrix: [[f32; 4]; 4] = (perspective_matrix * modelview_matrix).into();
+    let floor_passes = [
+        (glium::draw_parameters::DepthTest::IfLess, 1.0),
+        (
+            glium::draw_parameters::DepthTest::IfMore,
+            1.0 - SHADOW_OPACITY,
+        ),
+    ];
+    let floor_passes = if shadow_uniforms.is_some() {
+        &floor_passes[..]
+    } else {
+        &floor_passes[..1]
+    };
+
+    // Draw
+    // ----
+
+    let fxaa_enable = matches!(options.aamethod, AAMethod::FXAA);
+
+    fxaa::draw(&pipeline.fxaa, framebuffer, fxaa_enable, |target| {
+        // Fills background color and clears depth buffer
+        target.clear_color_and_depth(options.background, 1.0);
+        if let Some(ref shadow_uniforms) = shadow_uniforms {
+            target.draw(
+                &buffers.vertices,
+                &buffers.indices,
+                &pipeline.shadow_program,
+                shadow_uniforms,
+                &shadow_params,
+            )?;
+        }
+        if buffers.floor.len() > 0 && sees_ground(&options.camera) {
+            for &(test, shade) in floor_passes {
+                let params = glium::DrawParameters {
+                    depth: glium::Depth {
+                        test,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let uniforms = uniform! {
+                    matrix: floor_matrix,
+                    u_shade: shade,
+                };
+                target.draw(
+                    &buffers.floor,
+                    glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                    &pipeline.floor_program,
+                    &uniforms,
+                    &params,
+                )?;
+            }
+        }
+        // The model always sits on top of the ground
+        target.clear_depth(1.0);
+        target.draw(
+            (&buffers.vertices, &buffers.normals, &buffers.colors),
+            &buffers.indices,
+            &pipeline.program,
+            &uniforms,
+            &params,
+        )
+    })??;
+
+    // Convert Image
+    // =============
+
+    let pixels: glium::texture::RawImage2d<u8> = texture.read();
+    let img = image::ImageBuffer::from_raw(options.width, options.height, pixels.data.into_owned())
+        .ok_or_else(|| Error::Render("Unexpected texture size".to_string()))?;
+
+    Ok(image::DynamicImage::ImageRgba8(img).flipv())
+}
+
+enum GlDisplay {
+    Headless(glium::HeadlessRenderer),
+    Window {
+        display: glium::Display,
+        // The event loop must be kept alive as long as its window
+        _event_loop: Box<EventLoop<()>>,
+    },
+}
+
+impl GlDisplay {
+    fn context(&self) -> &Rc<glium::backend::Context> {
+        match self {
+            GlDisplay::Headless(display) => display.get_context(),
+            GlDisplay::Window { display, .. } => display.get_context(),
+        }
+    }
+}
+
+struct GlRenderer {
+    pipeline: Pipeline,
+    // Color and depth textures that are rendered into, recreated when the image size changes
+    target: Option<(glium::Texture2d, glium::texture::DepthTexture2d)>,
+    display: GlDisplay,
+}
+
+impl GlRenderer {
+    fn new(config: &Config) -> Result<GlRenderer, Error> {
+        // Create GL context
+        // =================
+        // 1. If not visible create a headless context.
+        // 2. If headless context creation fails, create a normal context with a hidden window.
+        let display = match create_headless_display(config) {
+            Ok(display) => GlDisplay::Headless(display),
+            Err(e) => {
+                warn!(
+                    "Unable to create headless GL context. Trying hidden window instead. Reason: {:?}",
+                    e
+                );
+                let (display, event_loop) = create_normal_display(config)?;
+                GlDisplay::Window {
+                    display,
+                    _event_loop: Box::new(event_loop),
+                }
+            }
+        };
+
+        Ok(GlRenderer {
+            pipeline: Pipeline::new(display.context())?,
+            target: None,
+            display,
+        })
+    }
+
+    // Renders every view with the same mesh buffers, see Renderer::render_views
+    fn render(
+        &mut self,
+        mesh: &Mesh,
+        views: &[RenderOptions],
+        matrices: Vec<(cgmath::Matrix4<f32>, cgmath::Matrix4<f32>)>,
+    ) -> Result<Vec<image::DynamicImage>, Error> {
+        let context = self.display.context();
+        let options = &views[0];
+
+        let resize = match self.target {
+            Some((ref texture, _)) => texture.dimensions() != (options.width, options.height),
+            None => true,
+        };
+        if resize {
+            let texture = glium::Texture2d::empty(context, options.width, options.height)?;
+            let depthtexture =
+                glium::texture::DepthTexture2d::empty(context, options.width, options.height)?;
+            self.target = Some((texture, depthtexture));
+        }
+        let (texture, depthtexture) = self.target.as_ref().unwrap();
+
+        let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::with_depth_buffer(
+            context,
+            texture,
+            depthtexture,
+        )?;
+        let buffers = MeshBuffers::new(context, options, mesh)?;
+        views
+            .iter()
+            .zip(matrices)
+            .map(|(view, matrices)| {
+                render_pipeline(
+                    &self.pipeline,
+                    view,
+                    mesh,
+                    &buffers,
+                    matrices,
+                    &mut framebuffer,
+                    texture,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Renders meshes into images.
+///
+/// Creating a GL context and compiling the shaders takes much longer than drawing a typical model.
+/// A `Renderer` does this once and can then be used for any number of renders, which makes it the
+/// better choice when thumbnailing many files.
+///
+/// # Example
+/// ```no_run
+/// use stl_thumb::config::{Config, RenderOptions};
+/// use stl_thumb::{Mesh, Renderer};
+///
+/// let config = Config::default();
+/// let mut renderer = Renderer::new(&config).unwrap();
+/// let options = RenderOptions::from(&config);
+/// for file in ["cube.stl", "benchy.stl"] {
+///     let mesh = Mesh::load(file, false).unwrap();
+///     let img = renderer.render(&mesh, &options).unwrap();
+/// }
+/// ```
+pub struct Renderer {
+    // None when using the software rasterizer
+    gl: Option<GlRenderer>,
+}
+
+impl Renderer {
+    /// Creates a renderer using the backend selected in `config`.
+    pub fn new(config: &Config) -> Result<Renderer, Error> {
+        let gl = match config.backend {
+            Backend::OpenGL => Some(GlRenderer::new(config)?),
+            Backend::Software => None,
+            Backend::Auto => match GlRenderer::new(config) {
+                Ok(gl) => Some(gl),
+                Err(e) => {
+                    warn!(
+                        "Unable to create any GL context. Falling back to software rendering. Reason: {:?}",
+                        e
+                    );
+                    None
+                }
+            },
+        };
+        if gl.is_none() {
+            info!("Using software renderer");
+        }
+        Ok(Renderer { gl })
+    }
+
+    /// Renders `mesh` into a new image.
+    pub fn render(
+        &mut self,
+        mesh: &Mesh,
+        options: &RenderOptions,
+    ) -> Result<image::DynamicImage, Error> {
+        let matrices = vec![scene_matrices(options, mesh)];
+        let mut images = self.render_views(mesh, slice::from_ref(options), matrices)?;
+        Ok(images.remove(0))
+    }
+
+    /// Renders `frames` images of `mesh` with the camera going once around it, starting from
+    /// the camera in `options`.
+    ///
+    /// The mesh is only uploaded once, and all frames are framed alike so the model keeps its
+    /// size while it turns.
+    pub fn render_turntable(
+        &mut self,
+        mesh: &Mesh,
+        options: &RenderOptions,
+        frames: u32,
+    ) -> Result<Vec<image::DynamicImage>, Error> {
+        let views: Vec<RenderOptions> = (0..frames)
+            .map(|i| {
+                let mut view = options.clone();
+                view.camera.azimuth += 360.0 * i as f32 / frames as f32;
+                view
+            })
+            .collect();
+        if views.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (modelviews, perspective_matrix) = view_matrices(&views, mesh);
+        let matrices = modelviews
+            .into_iter()
+            .map(|modelview_matrix| (modelview_matrix, perspective_matrix))
+            .collect();
+        self.render_views(mesh, &views, matrices)
+    }
+
+    /// Renders the views of `sheet` side by side into one image, which has the size given in
+    /// `options`.
+    ///
+    /// The mesh is only uploaded once. Each view is framed on its own.
+    pub fn render_sheet(
+        &mut self,
+        mesh: &Mesh,
+        options: &RenderOptions,
+        sheet: &Sheet,
+    ) -> Result<image::DynamicImage, Error> {
+        let views = sheet::cell_views(options, sheet);
+        let cells = if views.is_empty() {
+            Vec::new()
+        } else {
+            let matrices = views
+                .iter()
+                .map(|view| scene_matrices(view, mesh))
+                .collect();
+            self.render_views(mesh, &views, matrices)?
+        };
+        Ok(sheet::compose(options, sheet, &cells))
+    }
+
+    // Renders the mesh from each view with its model-view and perspective matrices.
+    // Everything but the camera comes from the first view.
+    fn render_views(
+        &mut self,
+        mesh: &Mesh,
+        views: &[RenderOptions],
+        matrices: Vec<(cgmath::Matrix4<f32>, cgmath::Matrix4<f32>)>,
+    ) -> Result<Vec<image::DynamicImage>, Error> {
+        match self.gl {
+            Some(ref mut gl) => gl.render(mesh, views, matrices),
+            None => Ok(views
+                .iter()
+                .zip(matrices)
+                .map(|(view, (modelview_matrix, perspective_matrix))| {
+                    software::render(
+                        view,
+                        mesh,
+                        modelview_matrix,
+                        perspective_matrix,
+                        shadow_matrix(view, mesh),
+                        &floor_triangles(view, mesh),
+                        LIGHT_DIR,
+                    )
+                })
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CAMERA_PRESETS;
+
+    fn camera_position(camera: &Camera) -> cgmath::Vector3<f32> {
+        let inverse = camera_view(camera).invert().unwrap();
+        (inverse * cgmath::Vector4::unit_w()).truncate()
+    }
+
+    #[test]
+    fn default_camera() {
+        let position = camera_position(&Camera {
+            distance: 4.899,
+            ..Default::default()
+        });
+        assert!((position - cgmath::Vector3::new(2.0, -4.0, 2.0)).magnitude() < 0.01);
+    }
+
+    #[test]
+    fn presets() {
+        for name in CAMERA_PRESETS {
+            let camera = Camera::preset(name).unwrap();
+            // Looking straight down or up must not break the view matrix
+            assert!(camera_view(&camera).is_invertible(), "{}", name);
+        }
+        let top = camera_position(&Camera {
+            distance: 4.899,
+            ..Camera::preset("top").unwrap()
+        });
+        assert!(top.z > 4.8 && top.x.abs() < 0.01 && top.y.abs() < 0.01);
+    }
+
+    #[test]
+    fn orthographic_fits_model() {
+        let mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        let options = RenderOptions {
+            width: 200,
+            height: 100,
+            camera: Camera {
+                projection: Projection::Orthographic,
+                ..Camera::preset("front").unwrap()
+            },
+            ..Default::default()
+        };
+        let (modelview, projection) = scene_matrices(&options, &mesh);
+        let (min, max) = view_bounds(&(projection * modelview), framed_points(&mesh, None, &[]));
+        // The 20 x 20 x 10 box seen from the front has the same aspect ratio as the image,
+        // so it fills it up to the padding on all sides
+        let fill = 1.0 - options.camera.padding / 50.0;
+        assert!((max.y - fill).abs() < 0.001 && (min.y + fill).abs() < 0.001);
+        assert!((max.x - fill).abs() < 0.001 && (min.x + fill).abs() < 0.001);
+        assert!(min.z > -1.0 && max.z < 1.0);
+    }
+
+    #[test]
+    fn perspective_fits_model() {
+        let mesh = Mesh::load("test_data/sphere.stl", false).unwrap();
+        for (width, height) in [(100, 100), (300, 100), (100, 300)] {
+            let options = RenderOptions {
+                width,
+                height,
+                camera: Camera {
+                    padding: 10.0,
+                    ..Camera::preset("iso").unwrap()
+                },
+                ..Default::default()
+            };
+            let (modelview, projection) = scene_matrices(&options, &mesh);
+            let mut min = cgmath::Vector2::new(f32::MAX, f32::MAX);
+            let mut max = cgmath::Vector2::new(f32::MIN, f32::MIN);
+            for v in &mesh.vertices {
+                let p = (projection * modelview).transform_point(cgmath::Point3::from(v.position));
+                min = cgmath::Vector2::new(min.x.min(p.x), min.y.min(p.y));
+                max = cgmath::Vector2::new(max.x.max(p.x), max.y.max(p.y));
+                assert!(p.z > -1.0 && p.z < 1.0);
+            }
+            // Inside the padding, and touching it on the limiting axis. The other axis is
+            // centered in the frustum rather than on screen, so it is only roughly centered.
+            let extent = max.x.max(max.y).max(-min.x).max(-min.y);
+            assert!((extent - 0.8).abs() < 0.001, "{:?} {:?}", min, max);
+            assert!((min + max).magnitude() < 0.01, "{:?} {:?}", min, max);
+        }
+    }
+
+    #[test]
+    fn shadow_lies_on_ground() {
+        let mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        let options = RenderOptions {
+            shadow: true,
+            ..Default::default()
+        };
+        let shadow = shadow_matrix(&options, &mesh).unwrap();
+        for v in &mesh.vertices {
+            let p = shadow.transform_point(cgmath::Point3::from(v.position));
+            assert!((p.z - mesh.bounds.min.z).abs() < 0.001);
+        }
+        // The framing leaves room for the shadow
+        let (modelview, projection) = scene_matrices(&options, &mesh);
+        let points = framed_points(&mesh, Some(&shadow), &[]);
+        let (min, max) = view_bounds(&(projection * modelview), points);
+        assert!(min.x >= -1.0 && min.y >= -1.0 && max.x <= 1.0 && max.y <= 1.0);
+    }
+}
@@ -0,0 +1,578 @@
+// HTTP Server
+// ===========
+//
+// Renders thumbnails on request, for web applications that show models. The model is uploaded
+// in the request body, or named by a path in the root directory:
+//
+//     POST /render?size=256                  model in the body, or a multipart/form-data file
+//     GET  /render?path=parts/gear.stl       model in the root directory
+//
+// Query fields are named like the command line options they set. Each worker thread has its own
+// Renderer and only takes the next request when it is done with one, so no more than `workers`
+// models are rendered at the same time. Other connections wait in the queue of the server.
+// Uploads are read without a timeout, so a slow client keeps its worker busy until it is done.
+
+use crate::config::{hex_color, Camera, Config, Material, RenderOptions, ServeConfig};
+use crate::error::Error;
+use crate::format::ModelFormat;
+use crate::mesh::Mesh;
+use crate::{encode_image, prepare_mesh, uri, Renderer};
+use image::ImageFormat;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::{fs, thread};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// An error response
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new<S: ToString>(status: u16, message: S) -> Failure {
+        Failure {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        let status = match e {
+            Error::UnsupportedFormat(_)
+            | Error::UnknownFormat
+            | Error::EmptyModel
+            | Error::Parse { .. } => 422,
+            Error::OutsideRoot(_) => 403,
+            Error::Io(ref e) if e.kind() == io::ErrorKind::NotFound => 404,
+            _ => 500,
+        };
+        Failure::new(status, e)
+    }
+}
+
+/// Listens on the address in `serve` and renders thumbnails for requests.
+///
+/// Rendering options that are not given in a request come from `config`. Only returns if the
+/// server can't be started, failed requests are answered with an error and logged.
+pub fn run(config: &Config, serve: &ServeConfig) -> Result<(), Error> {
+    let server = Server::http(&serve.address).map_err(|e| Error::Io(io::Error::other(e)))?;
+    info!("Listening on http://{}", server.server_addr());
+    serve_requests(config, serve, Arc::new(server))
+}
+
+fn serve_requests(config: &Config, serve: &ServeConfig, server: Arc<Server>) -> Result<(), Error> {
+    let (started, startup) = mpsc::channel();
+    let workers: Vec<_> = (0..serve.workers)
+        .map(|_| {
+            let (config, serve, server) = (config.clone(), serve.clone(), server.clone());
+            let started = started.clone();
+            thread::spawn(move || {
+                // GL contexts belong to the thread that created them, so each worker makes its
+                // own and reports whether that worked before taking requests
+                let mut renderer = match Renderer::new(&config) {
+                    Ok(renderer) => renderer,
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    }
+                };
+                // Dropped, so the startup check doesn't wait for the workers to end
+                let _ = started.send(Ok(()));
+                drop(started);
+                loop {
+                    let request = match server.recv() {
+                        Ok(request) => request,
+                        Err(e) => {
+                            warn!("Unable to receive request. Reason: {}", e);
+                            continue;
+                        }
+                    };
+                    // A request dropped by the panic is answered with a 500
+                    let render = || respond(&config, &serve, &mut renderer, request);
+                    if panic::catch_unwind(AssertUnwindSafe(render)).is_err() {
+                        error!("Worker panicked while rendering a request");
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(started);
+
+    // Every worker has to be ready, so a server that can't render fails right away
+    let mut ready = 0;
+    for result in startup {
+        result?;
+        ready += 1;
+    }
+    if ready < workers.len() {
+        return Err(Error::Render(
+            "Worker thread panicked while starting".to_string(),
+        ));
+    }
+    // Workers don't stop, so this serves until the process ends
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn respond(config: &Config, serve: &ServeConfig, renderer: &mut Renderer, mut request: Request) {
+    let response = match render_request(config, serve, renderer, &mut request) {
+        Ok((data, format)) => {
+            Response::from_data(data).with_header(header("Content-Type", format.to_mime_type()))
+        }
+        Err(failure) => {
+            info!(
+                "{} {}: {}",
+                request.method(),
+                request.url(),
+                failure.message
+            );
+            Response::from_string(failure.message)
+                .with_status_code(failure.status)
+                .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+        }
+    };
+    if let Err(e) = request.respond(response) {
+        warn!("Unable to send response. Reason: {}", e);
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("Invalid header")
+}
+
+// Renders the model of a request, returning the encoded image and its format
+fn render_request(
+    config: &Config,
+    serve: &ServeConfig,
+    renderer: &mut Renderer,
+    request: &mut Request,
+) -> Result<(Vec<u8>, ImageFormat), Failure> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    if path != "/render" {
+        return Err(Failure::new(404, "Not found"));
+    }
+
+    let mut config = config.clone();
+    let mut model_path = None;
+    for (field, value) in query_fields(query).map_err(|e| Failure::new(400, e))? {
+        match field.as_str() {
+            "path" => model_path = Some(value),
+            _ => set_field(&mut config, serve.max_size, &field, &value)
+                .map_err(|e| Failure::new(400, e))?,
+        }
+    }
+    if !matches!(
+        config.format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP
+    ) {
+        return Err(Failure::new(400, "Images can only be PNG, JPEG or WebP"));
+    }
+
+    let mesh = match *request.method() {
+        Method::Get => {
+            let path = model_path.ok_or_else(|| Failure::new(400, "No path given"))?;
+            load_path(&config, serve, &path)?
+        }
+        Method::Post => load_upload(&config, serve, request)?,
+        _ => return Err(Failure::new(405, "Use GET with a path, or POST a model")),
+    };
+    let options = RenderOptions::from(&config);
+    let img = match config.sheet {
+        Some(ref sheet) => renderer.render_sheet(&mesh, &options, sheet)?,
+        None => renderer.render(&mesh, &options)?,
+    };
+    Ok((encode_image(&img, &config.format, None)?, config.format))
+}
+
+// Loads a model from the root directory. Symlinks are resolved before checking that the model is
+// in the root, so they can't lead out of it either. Files the model references have to be in the
+// root too.
+fn load_path(config: &Config, serve: &ServeConfig, path: &str) -> Result<Mesh, Failure> {
+    let root = serve
+        .root
+        .as_ref()
+        .ok_or_else(|| Failure::new(403, "Models can only be uploaded"))?;
+    let root = fs::canonicalize(root).map_err(Error::from)?;
+    let model = fs::canonicalize(root.join(path))
+        .map_err(|_| Failure::new(404, format!("{} not found", path)))?;
+    if !model.starts_with(&root) || !model.is_file() {
+        return Err(Failure::new(
+            403,
+            format!("{} is not a model in the root", path),
+        ));
+    }
+    let mesh = Mesh::load_within(
+        &uri::file_uri(&model)?,
+        config.input_format,
+        Some(&root),
+        config.recalc_normals,
+    )?;
+    Ok(prepare_mesh(mesh, config))
+}
+
+// Loads an uploaded model. Bodies are only read up to the upload limit.
+fn load_upload(
+    config: &Config,
+    serve: &ServeConfig,
+    request: &mut Request,
+) -> Result<Mesh, Failure> {
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| h.value.as_str().to_string())
+        .unwrap_or_default();
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(serve.max_upload + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| Failure::new(400, e))?;
+    if body.len() as u64 > serve.max_upload {
+        return Err(Failure::new(
+            413,
+            format!("Models can be up to {} bytes", serve.max_upload),
+        ));
+    }
+
+    let (data, file_name) = if content_type.starts_with("multipart/form-data") {
+        let (data, file_name) = multipart_file(&body, &content_type)
+            .ok_or_else(|| Failure::new(400, "No model in the form"))?;
+        (data.to_vec(), file_name)
+    } else {
+        (body, None)
+    };
+    // The file name of an upload is only a hint, like the extension of a file
+    let format = config.input_format.or_else(|| {
+        ModelFormat::detect(&data, data.len() as u64).or_else(|| {
+            let name = file_name?;
+            ModelFormat::from_extension(&Path::new(&name).extension()?.to_str()?.to_lowercase())
+        })
+    });
+    let mesh = Mesh::from_buffer(data, format, config.recalc_normals)?;
+    Ok(prepare_mesh(mesh, config))
+}
+
+// Sets the rendering option for a query field, sizes are limited to max_size
+fn set_field(config: &mut Config, max_size: u32, field: &str, value: &str) -> Result<(), String> {
+    match field {
+        "size" => match value.parse::<u32>() {
+            Ok(size) if (1..=max_size).contains(&size) => {
+                config.width = size;
+                config.height = size;
+            }
+            _ => return Err(format!("Size must be between 1 and {}", max_size)),
+        },
+        "view" => {
+            config.camera =
+                Camera::preset(value).ok_or_else(|| format!("Unknown view {}", value))?
+        }
+        "azimuth" | "elevation" => {
+            let angle = value
+                .parse::<f32>()
+                .ok()
+                .filter(|angle| angle.is_finite())
+                .ok_or_else(|| format!("Invalid {} {}", field, value))?;
+            match field {
+                "azimuth" => config.camera.azimuth = angle,
+                _ => config.camera.elevation = angle,
+            }
+        }
+        "material" => {
+            let colors = value
+                .split(',')
+                .map(hex_color::<3>)
+                .collect::<Option<Vec<_>>>()
+                .filter(|colors| colors.len() == 3)
+                .ok_or("Material must be three rgb hex colors: ambient, diffuse and specular")?;
+            config.material = Material {
+                ambient: colors[0],
+                diffuse: colors[1],
+                specular: colors[2],
+            };
+        }
+        "background" => {
+            let [r, g, b, a] =
+                hex_color::<4>(value).ok_or("Background must be an rgba hex color")?;
+            config.background = (r, g, b, a);
+        }
+        "format" => {
+            config.format = match value.to_lowercase().as_str() {
+                "png" => ImageFormat::Png,
+                "jpeg" | "jpg" => ImageFormat::Jpeg,
+                "webp" => ImageFormat::WebP,
+                _ => return Err(format!("Unsupported image format {}", value)),
+            }
+        }
+        "input-format" => {
+            config.input_format = Some(
+                ModelFormat::from_extension(value)
+                    .ok_or_else(|| format!("Unsupported model format {}", value))?,
+            )
+        }
+        _ => return Err(format!("Unknown field {}", field)),
+    }
+    Ok(())
+}
+
+// Decoded fields of a query string
+fn query_fields(query: &str) -> Result<Vec<(String, String)>, String> {
+    let decode = |s: &str| {
+        uri::percent_decode(&s.replace('+', " "))
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| format!("Invalid query {}", query))
+    };
+    query
+        .split('&')
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            Ok((decode(name)?, decode(value)?))
+        })
+        .collect()
+}
+
+// Contents and file name of the model in a multipart/form-data body. That is the first part with a
+// file name, or one called model.
+fn multipart_file<'a>(body: &'a [u8], content_type: &str) -> Option<(&'a [u8], Option<String>)> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("\r\n--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+    // The first delimiter doesn't need a line break before it
+    let mut rest = &body[find(body, &delimiter[2..])? + delimiter.len() - 2..];
+
+    // The last delimiter is followed by --
+    while !rest.starts_with(b"--") {
+        let end = find(rest, delimiter)?;
+        let part = rest[..end].strip_prefix(b"\r\n")?;
+        rest = &rest[end + delimiter.len()..];
+
+        let header_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let disposition = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("Content-Disposition")
+                .then_some(value)
+        })?;
+        let param = |key: &str| {
+            disposition.split(';').find_map(|param| {
+                let value = param.trim().strip_prefix(key)?.strip_prefix('=')?;
+                Some(value.trim_matches('"').to_string())
+            })
+        };
+        let file_name = param("filename");
+        if file_name.is_some() || param("name").as_deref() == Some("model") {
+            return Some((&part[header_end + 4..], file_name));
+        }
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Backend;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    #[test]
+    fn fields() {
+        let mut config = Config::default();
+        let query =
+            "size=128&view=top&material=000000,ff8000,ffffff&background=ff000080&format=jpg";
+        for (field, value) in query_fields(query).unwrap() {
+            set_field(&mut config, 1024, &field, &value).unwrap();
+        }
+        assert_eq!((128, 128), (config.width, config.height));
+        assert_eq!(Camera::preset("top").unwrap(), config.camera);
+        assert_eq!([1.0, 128.0 / 255.0, 0.0], config.material.diffuse);
+        assert_eq!((1.0, 0.0, 0.0, 128.0 / 255.0), config.background);
+        assert_eq!(ImageFormat::Jpeg, config.format);
+
+        assert_eq!(
+            vec![("path".to_string(), "my parts/a b.stl".to_string())],
+            query_fields("path=my+parts%2Fa%20b.stl").unwrap()
+        );
+        for (field, value) in [
+            ("size", "0"),
+            ("size", "1025"),
+            ("view", "sideways"),
+            ("material", "ff8000"),
+            ("background", "ffffff"),
+            ("format", "bmp"),
+            ("scale", "2"),
+        ] {
+            assert!(
+                set_field(&mut config, 1024, field, value).is_err(),
+                "{}",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn multipart() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"size\"\r\n\r\n\
+            256\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"gear.stl\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            solid gear\r\n\r\nendsolid\r\n\
+            --XyZ--\r\n";
+        let (data, file_name) =
+            multipart_file(body, "multipart/form-data; boundary=\"XyZ\"").unwrap();
+        assert_eq!(b"solid gear\r\n\r\nendsolid", data);
+        assert_eq!(Some("gear.stl".to_string()), file_name);
+        assert!(multipart_file(body, "multipart/form-data; boundary=Other").is_none());
+    }
+
+    // Sends a request and returns the status and body of the response
+    fn send(address: &str, method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n",
+            method,
+            target,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let header_end = find(&response, b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap();
+        (status, response[header_end + 4..].to_vec())
+    }
+
+    #[test]
+    fn server() {
+        let model = fs::read("test_data/cube.stl").unwrap();
+        let config = Config {
+            backend: Backend::Software,
+            ..Default::default()
+        };
+        let serve = ServeConfig {
+            address: "127.0.0.1:0".to_string(),
+            root: Some("test_data".into()),
+            workers: 2,
+            max_upload: model.len() as u64,
+            max_size: 64,
+        };
+        let server = Arc::new(Server::http(&serve.address).unwrap());
+        let address = server.server_addr().to_ip().unwrap().to_string();
+        thread::spawn(move || serve_requests(&config, &serve, server));
+
+        let (status, body) = send(&address, "POST", "/render?size=64", &model);
+        assert_eq!(200, status);
+        assert_eq!(64, image::load_from_memory(&body).unwrap().width());
+        assert_eq!(400, send(&address, "POST", "/render?size=65", &model).0);
+
+        let (status, body) = send(&address, "GET", "/render?path=cube.obj&format=webp", &[]);
+        assert_eq!(200, status);
+        assert_eq!(ImageFormat::WebP, image::guess_format(&body).unwrap());
+
+        assert_eq!(
+            403,
+            send(&address, "GET", "/render?path=../Cargo.toml", &[]).0
+        );
+        assert_eq!(
+            404,
+            send(&address, "GET", "/render?path=missing.stl", &[]).0
+        );
+        assert_eq!(404, send(&address, "GET", "/thumbnail", &[]).0);
+        let mut large = model.clone();
+        large.push(b'\n');
+        assert_eq!(413, send(&address, "POST", "/render", &large).0);
+        let (status, body) = send(&address, "POST", "/render", b"not a model");
+        assert_eq!(422, status);
+        assert_eq!(b"Unrecognized model data".as_slice(), body.trim_ascii_end());
+    }
+
+    #[test]
+    fn references_outside_root() {
+        let dir = std::env::temp_dir().join(format!("stl-thumb-serve-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("secret.bin"), [0; 64]).unwrap();
+        fs::copy("test_data/cube.mtl", dir.join("secret.mtl")).unwrap();
+        fs::copy("test_data/cube.mtl", root.join("cube.mtl")).unwrap();
+        fs::copy("test_data/cube.obj", root.join("cube.obj")).unwrap();
+        let obj = fs::read_to_string("test_data/cube.obj").unwrap();
+        fs::write(
+            root.join("outside.obj"),
+            obj.replace("mtllib cube.mtl", "mtllib ../secret.mtl"),
+        )
+        .unwrap();
+        // The data URI of the buffer replaced by a reference
+        let gltf = fs::read_to_string("test_data/cube.gltf").unwrap();
+        let start = gltf.find("data:").unwrap();
+        let end = start + gltf[start..].find('"').unwrap();
+        let secret = uri::file_uri(&fs::canonicalize(dir.join("secret.bin")).unwrap()).unwrap();
+        for (name, reference) in [("relative.gltf", "../secret.bin"), ("file.gltf", &secret)] {
+            let model = format!("{}{}{}", &gltf[..start], reference, &gltf[end..]);
+            fs::write(root.join(name), model).unwrap();
+        }
+
+        let config = Config::default();
+        let serve = ServeConfig {
+            address: "127.0.0.1:0".to_string(),
+            root: Some(root),
+            workers: 1,
+            max_upload: 1024,
+            max_size: 1024,
+        };
+        assert!(load_path(&config, &serve, "cube.obj").is_ok());
+        for name in ["outside.obj", "relative.gltf", "file.gltf"] {
+            match load_path(&config, &serve, name) {
+                Err(failure) => assert_eq!(403, failure.status, "{}", name),
+                Ok(_) => panic!("{} was loaded", name),
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renderer_failure() {
+        let config = Config {
+            backend: Backend::OpenGL,
+            ..Default::default()
+        };
+        // Only testable where OpenGL is missing
+        if Renderer::new(&config).is_ok() {
+            return;
+        }
+        let serve = ServeConfig {
+            address: "127.0.0.1:0".to_string(),
+            root: None,
+            workers: 2,
+            max_upload: 1024,
+            max_size: 1024,
+        };
+        let server = Arc::new(Server::http(&serve.address).unwrap());
+        assert!(serve_requests(&config, &serve, server).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+#version 120
+
+varying vec3 v_color;
+
+// Brightness, lower in the shadow
+uniform float u_shade;
+
+void main() {
+    gl_FragColor = vec4(v_color * u_shade, 1.0);
+}
@@ -0,0 +1,13 @@
+#version 120
+
+attribute vec3 position;
+attribute vec3 color;
+
+varying vec3 v_color;
+
+uniform mat4 matrix;
+
+void main() {
+    gl_Position = matrix * vec4(position, 1.0);
+    v_color = color;
+}
@@ -2,13 +2,12 @@
 
 varying vec3 v_normal;
 varying vec3 v_position;
+varying vec3 v_ambient;
+varying vec3 v_diffuse;
+varying vec3 v_specular;
 
 uniform vec3 u_light;
 
-uniform vec3 ambient_color;
-uniform vec3 diffuse_color;
-uniform vec3 specular_color;
-
 void main() {
     float diffuse = max(dot(normalize(v_normal), normalize(u_light)), 0.0);
 
@@ -21,6 +20,6 @@ void main() {
     // float cosAlpha = clamp( dot(camera_dir,R), 0, 1 );
     // float specular = pow( cosAlpha, 4.0 );
 
-    gl_FragColor = vec4(ambient_color + diffuse * diffuse_color + specular * specular_color, 1.0);
+    gl_FragColor = vec4(v_ambient + diffuse * v_diffuse + specular * v_specular, 1.0);
 }
 
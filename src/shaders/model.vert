@@ -2,9 +2,15 @@
 
 attribute vec3 position;
 attribute vec3 normal;
+attribute vec3 ambient;
+attribute vec3 diffuse;
+attribute vec3 specular;
 
 varying vec3 v_normal;
 varying vec3 v_position;
+varying vec3 v_ambient;
+varying vec3 v_diffuse;
+varying vec3 v_specular;
 
 uniform mat4 perspective;
 //uniform mat4 view;
@@ -21,5 +27,9 @@ void main() {
     v_position = p.xyz / p.w;
 
     v_normal = mat3(modelview) * normal;
+
+    v_ambient = ambient;
+    v_diffuse = diffuse;
+    v_specular = specular;
 }
 
@@ -0,0 +1,10 @@
+#version 120
+
+attribute vec3 position;
+
+// Flattens the model onto the ground, then projects it like the model
+uniform mat4 matrix;
+
+void main() {
+    gl_Position = matrix * vec4(position, 1.0);
+}
@@ -0,0 +1,194 @@
+// Contact Sheets
+// ==============
+//
+// Several views of one model in a grid, to review a part from all sides at once. Every cell
+// is rendered and framed on its own, then copied into the sheet. Labels use a small built-in
+// bitmap font, so no font files have to be installed.
+
+use crate::config::{RenderOptions, Sheet};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+
+// Glyphs of the printable ASCII characters from ' ' to '~', 5 x 7 pixels each, one byte per
+// row with the leftmost pixel in bit 4. Lower case letters share the upper case glyphs.
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const FONT: &[u8; 95 * 7] = include_bytes!("font.bin");
+// Cells this many pixels high get labels drawn with 1 pixel per glyph pixel
+const LABEL_SCALE_HEIGHT: u32 = 120;
+
+// Rows of the glyph for a character, characters outside of printable ASCII get the '?' glyph
+fn glyph(c: char) -> &'static [u8] {
+    let c = if (' '..='~').contains(&c) { c } else { '?' };
+    let start = (c as usize - ' ' as usize) * GLYPH_HEIGHT as usize;
+    &FONT[start..start + GLYPH_HEIGHT as usize]
+}
+
+// Columns and rows of the grid, and the size of each cell
+fn layout(options: &RenderOptions, sheet: &Sheet) -> (u32, u32, u32, u32) {
+    let count = sheet.views.len().max(1) as u32;
+    let columns = sheet.columns.clamp(1, count);
+    let rows = count.div_ceil(columns);
+    (
+        columns,
+        rows,
+        (options.width / columns).max(1),
+        (options.height / rows).max(1),
+    )
+}
+
+/// Options for rendering each cell of the sheet.
+pub(crate) fn cell_views(options: &RenderOptions, sheet: &Sheet) -> Vec<RenderOptions> {
+    let (_, _, width, height) = layout(options, sheet);
+    sheet
+        .views
+        .iter()
+        .map(|(_, camera)| RenderOptions {
+            width,
+            height,
+            camera: *camera,
+            ..options.clone()
+        })
+        .collect()
+}
+
+/// Puts the rendered cells together into one image the size given in `options`.
+pub(crate) fn compose(
+    options: &RenderOptions,
+    sheet: &Sheet,
+    cells: &[DynamicImage],
+) -> DynamicImage {
+    let (columns, _, width, height) = layout(options, sheet);
+    let (r, g, b, a) = options.background;
+    let background = Rgba([r, g, b, a].map(|c| (c * 255.0).round() as u8));
+    let mut img = RgbaImage::from_pixel(options.width, options.height, background);
+
+    let scale = (height / LABEL_SCALE_HEIGHT).max(1);
+    let color = label_color(options.background);
+    for (i, (cell, (label, _))) in cells.iter().zip(&sheet.views).enumerate() {
+        let x = (i as u32 % columns) * width;
+        let y = (i as u32 / columns) * height;
+        imageops::replace(&mut img, &cell.to_rgba8(), x as i64, y as i64);
+        if sheet.labels {
+            let margin = 3 * scale;
+            draw_text(
+                &mut img,
+                label,
+                x + margin,
+                y + margin,
+                x + width,
+                scale,
+                color,
+            );
+        }
+    }
+    DynamicImage::ImageRgba8(img)
+}
+
+// Dark text, unless the background is dark. Transparent backgrounds are usually shown on
+// light file manager windows.
+fn label_color(background: (f32, f32, f32, f32)) -> Rgba<u8> {
+    let (r, g, b, a) = background;
+    let luma = (0.299 * r + 0.587 * g + 0.114 * b) * a + (1.0 - a);
+    if luma > 0.5 {
+        Rgba([40, 40, 40, 255])
+    } else {
+        Rgba([230, 230, 230, 255])
+    }
+}
+
+// Writes text with its top left corner at (x, y), cut off at max_x
+fn draw_text(
+    img: &mut RgbaImage,
+    text: &str,
+    x: u32,
+    y: u32,
+    max_x: u32,
+    scale: u32,
+    color: Rgba<u8>,
+) {
+    let max_x = max_x.min(img.width());
+    let max_y = (y + GLYPH_HEIGHT * scale).min(img.height());
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        let left = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+        for py in y..max_y {
+            let row = rows[((py - y) / scale) as usize];
+            for px in left..(left + GLYPH_WIDTH * scale).min(max_x) {
+                if row & (1 << (GLYPH_WIDTH - 1 - (px - left) / scale)) != 0 {
+                    img.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Camera;
+
+    fn sheet(count: usize, columns: u32) -> Sheet {
+        Sheet {
+            views: vec![("front".to_string(), Camera::default()); count],
+            columns,
+            labels: true,
+        }
+    }
+
+    #[test]
+    fn grid_layout() {
+        let options = RenderOptions {
+            width: 300,
+            height: 200,
+            ..Default::default()
+        };
+        let views = cell_views(&options, &sheet(5, 3));
+        assert_eq!(5, views.len());
+        assert!(views.iter().all(|v| (v.width, v.height) == (100, 100)));
+        // Fewer views than columns shrinks the grid
+        let views = cell_views(&options, &sheet(2, 4));
+        assert!(views.iter().all(|v| (v.width, v.height) == (150, 200)));
+    }
+
+    #[test]
+    fn cells_and_labels() {
+        let options = RenderOptions {
+            width: 40,
+            height: 20,
+            background: (1.0, 1.0, 1.0, 1.0),
+            ..Default::default()
+        };
+        let mut sheet = sheet(2, 2);
+        let red = Rgba([255, 0, 0, 255]);
+        let cells: Vec<_> = (0..2)
+            .map(|_| DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 20, red)))
+            .collect();
+
+        sheet.labels = false;
+        let img = compose(&options, &sheet, &cells).to_rgba8();
+        assert!(img.pixels().all(|p| *p == red));
+
+        sheet.labels = true;
+        let img = compose(&options, &sheet, &cells).to_rgba8();
+        let dark = Rgba([40, 40, 40, 255]);
+        // The top bar of the F starts 3 pixels into each cell
+        for x in [3, 4, 5, 6, 7, 23, 24, 25, 26, 27] {
+            assert_eq!(dark, *img.get_pixel(x, 3));
+        }
+        assert_eq!(red, *img.get_pixel(2, 3));
+        // The N would start at x = 21, but labels are cut off at the edge of their cell
+        assert_eq!(red, *img.get_pixel(21, 6));
+    }
+
+    #[test]
+    fn printable_ascii_glyphs() {
+        for c in '!'..='~' {
+            assert!(glyph(c).iter().any(|row| *row != 0), "{c:?} has no glyph");
+            assert!(glyph(c).iter().all(|row| *row < 1 << GLYPH_WIDTH));
+        }
+        assert!(glyph(' ').iter().all(|row| *row == 0));
+        assert_eq!(glyph('A'), glyph('a'));
+        assert_eq!(glyph('?'), glyph('ü'));
+        assert_eq!(glyph('?'), glyph('\t'));
+    }
+}
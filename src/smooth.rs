@@ -0,0 +1,223 @@
+// Smooth Shading
+// ==============
+//
+// Most model formats store each triangle on its own, so the mesh has no idea which faces
+// are neighbours. Vertices at the same position are welded together to find them, then
+// every corner gets the average normal of the faces around it that are close enough in
+// angle to its own face. Edges sharper than the crease angle keep separate normals.
+
+use crate::config::Material;
+use crate::mesh::{key_bits, material_bits, Mesh, Normal, Vertex};
+use ahash::AHashMap;
+use cgmath::{InnerSpace, Vector3};
+
+// Vertices closer than this share a position, relative to the size of the model
+const WELD_TOLERANCE: f32 = 1e-5;
+
+// Welded position, normal and color of a vertex, compared bit for bit
+type VertexKey = (usize, [u32; 3], Option<[u32; 9]>);
+
+pub(crate) struct Smoothed {
+    pub vertices: Vec<Vertex>,
+    pub normals: Vec<Normal>,
+    pub colors: Vec<Option<Material>>,
+    pub indices: Vec<u32>,
+}
+
+pub(crate) fn smooth(mesh: &Mesh, crease_angle: f32) -> Smoothed {
+    let corners: Vec<usize> = mesh.triangles().flatten().collect();
+    let position = |v: usize| Vector3::from(mesh.vertices[v].position);
+
+    // Weld
+    // ----
+
+    // Positions are put in a grid as large as the tolerance. Close positions can be on either
+    // side of a cell boundary, so the cells around are searched as well.
+    let size = (mesh.bounds.max - mesh.bounds.min).magnitude();
+    let tolerance = (size * WELD_TOLERANCE).max(f32::MIN_POSITIVE);
+    let mut cells: AHashMap<[i64; 3], Vec<usize>> = AHashMap::new();
+    let mut welded_positions: Vec<Vector3<f32>> = Vec::new();
+    let welded: Vec<usize> = corners
+        .iter()
+        .map(|&v| {
+            let p = position(v);
+            let key = [p.x, p.y, p.z].map(|x| (x / tolerance).floor() as i64);
+            let near = neighbours(key).find_map(|key| {
+                cells
+                    .get(&key)?
+                    .iter()
+                    .copied()
+                    .find(|&w| (welded_positions[w] - p).magnitude() <= tolerance)
+            });
+            near.unwrap_or_else(|| {
+                let w = welded_positions.len();
+                welded_positions.push(p);
+                cells.entry(key).or_default().push(w);
+                w
+            })
+        })
+        .collect();
+
+    // Corners at each welded position, stored back to back
+    let mut start = vec![0; welded_positions.len() + 1];
+    for &w in &welded {
+        start[w + 1] += 1;
+    }
+    for i in 1..start.len() {
+        start[i] += start[i - 1];
+    }
+    let mut fill = start.clone();
+    let mut around = vec![0; corners.len()];
+    for (c, &w) in welded.iter().enumerate() {
+        around[fill[w]] = c;
+        fill[w] += 1;
+    }
+
+    // Face normals and corner angles
+    // ------------------------------
+
+    let mut face_normals = Vec::with_capacity(corners.len() / 3);
+    let mut angles = Vec::with_capacity(corners.len());
+    for tri in corners.chunks_exact(3) {
+        let p = [0, 1, 2].map(|i| position(tri[i]));
+        let n = (p[1] - p[0]).cross(p[2] - p[0]);
+        // Degenerate faces have no direction and don't count towards their neighbours
+        let area = n.magnitude();
+        face_normals.push(if area > 0.0 {
+            n / area
+        } else {
+            Vector3::new(0.0, 0.0, 0.0)
+        });
+        for i in 0..3 {
+            let u = p[(i + 1) % 3] - p[i];
+            let v = p[(i + 2) % 3] - p[i];
+            let cos = u.dot(v) / (u.magnitude() * v.magnitude());
+            angles.push(if cos.is_finite() {
+                cos.clamp(-1.0, 1.0).acos()
+            } else {
+                0.0
+            });
+        }
+    }
+
+    // Corner normals
+    // --------------
+
+    let min_cos = crease_angle.to_radians().cos();
+    let mut smoothed = Smoothed {
+        vertices: Vec::new(),
+        normals: Vec::new(),
+        colors: Vec::new(),
+        indices: Vec::with_capacity(corners.len()),
+    };
+    // Corners with the same position, normal and color become one vertex
+    let mut unique: AHashMap<VertexKey, u32> = AHashMap::new();
+    for (c, &v) in corners.iter().enumerate() {
+        let face = face_normals[c / 3];
+        let w = welded[c];
+        let mut n = Vector3::new(0.0, 0.0, 0.0);
+        for &other in &around[start[w]..start[w + 1]] {
+            let other_face = face_normals[other / 3];
+            if face.dot(other_face) >= min_cos {
+                n += other_face * angles[other];
+            }
+        }
+        let n = if n.magnitude2() > 0.0 {
+            n.normalize()
+        } else {
+            // Degenerate face, keep whatever normal it had
+            Vector3::from(mesh.normals[v].normal)
+        };
+
+        let color = mesh.colors.get(v).copied().flatten();
+        let key = (
+            w,
+            [n.x, n.y, n.z].map(key_bits),
+            color.as_ref().map(material_bits),
+        );
+        let index = *unique.entry(key).or_insert_with(|| {
+            smoothed.vertices.push(mesh.vertices[v]);
+            smoothed.normals.push(Normal { normal: n.into() });
+            if !mesh.colors.is_empty() {
+                smoothed.colors.push(color);
+            }
+            smoothed.vertices.len() as u32 - 1
+        });
+        smoothed.indices.push(index);
+    }
+    smoothed
+}
+
+// A cell and the 26 around it
+fn neighbours(key: [i64; 3]) -> impl Iterator<Item = [i64; 3]> {
+    (0..27)
+        .map(move |i| [i % 3, i / 3 % 3, i / 9].map(|d| d - 1))
+        .map(move |d| [key[0] + d[0], key[1] + d[1], key[2] + d[2]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WELD_TOLERANCE;
+    use crate::mesh::Mesh;
+    use cgmath::{EuclideanSpace, InnerSpace, Vector3};
+
+    #[test]
+    fn sphere_is_smooth() {
+        let mut mesh = Mesh::load("test_data/sphere.stl", false).unwrap();
+        let triangles = mesh.triangles().count();
+        mesh.smooth_normals(60.0);
+        assert_eq!(triangles, mesh.triangles().count());
+        // Every position is shared by the faces around it
+        assert!(mesh.vertices.len() * 4 < triangles * 3);
+        // Normals of a sphere centered on the bounding box point straight out
+        let center = mesh.bounds.center().to_vec();
+        for (v, n) in mesh.vertices.iter().zip(&mesh.normals) {
+            let out = (Vector3::from(v.position) - center).normalize();
+            assert!(out.dot(Vector3::from(n.normal)) > 0.99);
+        }
+    }
+
+    #[test]
+    fn cube_keeps_edges() {
+        let mut mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        mesh.smooth_normals(30.0);
+        // Each of the 8 corners has a separate vertex for each of its 3 faces
+        assert_eq!(24, mesh.vertices.len());
+        for n in &mesh.normals {
+            let n = Vector3::from(n.normal);
+            assert!(n.x.abs().max(n.y.abs()).max(n.z.abs()) > 0.999);
+        }
+    }
+
+    #[test]
+    fn weld_across_cells() {
+        // Two faces folded slightly along x = 0, with the corners of the fold a fifth of the
+        // tolerance apart but on either side of half a cell
+        let size = (4.0f32 + 1.0 + 0.01).sqrt();
+        let tolerance = size * WELD_TOLERANCE;
+        let (a, b) = (0.4 * tolerance, 0.6 * tolerance);
+        let stl = format!(
+            "solid fold
+facet normal 0 0 1
+outer loop
+vertex -1 0 0.1
+vertex {a} 0 0
+vertex {a} 1 0
+endloop
+endfacet
+facet normal 0 0 1
+outer loop
+vertex {b} 0 0
+vertex 1 0 0.1
+vertex {b} 1 0
+endloop
+endfacet
+endsolid fold
+"
+        );
+        let mut mesh = Mesh::from_buffer(stl.into_bytes(), None, false).unwrap();
+        mesh.smooth_normals(60.0);
+        // The corners of the fold are shared, so it has no seam
+        assert_eq!(4, mesh.vertices.len());
+    }
+}
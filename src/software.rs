@@ -0,0 +1,412 @@
+// Software rasterizer
+// ===================
+//
+// Used when no OpenGL context can be created at all (no GPU, no X server, no osmesa).
+// It follows shaders/model.vert and shaders/model.frag as closely as possible so
+// the output is framed and lit the same as the GL path. Instead of FXAA it renders
+// at a higher resolution and averages the samples down.
+
+use crate::config::{AAMethod, RenderOptions, SHADOW_OPACITY};
+use crate::floor::FloorVertex;
+use crate::mesh::Mesh;
+use crate::renderer::{shadow_color, vertex_colors};
+use cgmath::{InnerSpace, Matrix3, Matrix4, Vector3, Vector4};
+
+// Samples per pixel along each axis when anti-aliasing is enabled
+const SUPERSAMPLE: u32 = 2;
+
+#[derive(Copy, Clone)]
+struct ClipVertex {
+    clip: Vector4<f32>,
+    // Position and normal in view space, used for lighting
+    position: Vector3<f32>,
+    normal: Vector3<f32>,
+    // Ambient, diffuse and specular color
+    color: [Vector3<f32>; 3],
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            clip: self.clip + (other.clip - self.clip) * t,
+            position: self.position + (other.position - self.position) * t,
+            normal: self.normal + (other.normal - self.normal) * t,
+            color: [0, 1, 2].map(|i| self.color[i] + (other.color[i] - self.color[i]) * t),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum DepthTest {
+    Overwrite,
+    IfLess,
+    IfMore,
+}
+
+// Same meaning as the glium draw parameters used for each pass
+#[derive(Copy, Clone)]
+struct DrawParams {
+    cull_clockwise: bool,
+    depth_test: DepthTest,
+    depth_write: bool,
+    depth_range: (f32, f32),
+}
+
+impl Default for DrawParams {
+    fn default() -> Self {
+        DrawParams {
+            cull_clockwise: false,
+            depth_test: DepthTest::Overwrite,
+            depth_write: false,
+            depth_range: (0.0, 1.0),
+        }
+    }
+}
+
+// Screen space vertex, ready for scan conversion
+struct ScreenVertex {
+    x: f32,
+    y: f32,
+    depth: f32,
+    inv_w: f32,
+    vertex: ClipVertex,
+}
+
+struct Target {
+    width: u32,
+    height: u32,
+    color: Vec<[f32; 4]>,
+    depth: Vec<f32>,
+}
+
+impl Target {
+    fn new(width: u32, height: u32, background: (f32, f32, f32, f32)) -> Target {
+        let size = (width * height) as usize;
+        Target {
+            width,
+            height,
+            color: vec![[background.0, background.1, background.2, background.3]; size],
+            depth: vec![1.0; size],
+        }
+    }
+
+    fn draw_triangle<S>(&mut self, tri: [&ClipVertex; 3], params: DrawParams, shade: &S)
+    where
+        S: Fn(Vector3<f32>, Vector3<f32>, [Vector3<f32>; 3]) -> [f32; 4],
+    {
+        let verts = tri.map(|v| {
+            let inv_w = 1.0 / v.clip.w;
+            let ndc = v.clip.truncate() * inv_w;
+            ScreenVertex {
+                x: (ndc.x * 0.5 + 0.5) * self.width as f32,
+                // Image rows go top to bottom, NDC goes bottom to top
+                y: (0.5 - ndc.y * 0.5) * self.height as f32,
+                depth: ndc.z * 0.5 + 0.5,
+                inv_w,
+                vertex: *v,
+            }
+        });
+        let [a, b, c] = &verts;
+
+        // Signed area is negative for counter clockwise triangles because the y axis is flipped.
+        // The model culls clockwise faces, same as the GL pipeline.
+        let area = edge(a, b, c.x, c.y);
+        if area == 0.0 || (params.cull_clockwise && area > 0.0) {
+            return;
+        }
+
+        let min_x = a.x.min(b.x).min(c.x).floor().max(0.0) as u32;
+        let min_y = a.y.min(b.y).min(c.y).floor().max(0.0) as u32;
+        let max_x = (a.x.max(b.x).max(c.x).ceil() as u32).min(self.width);
+        let max_y = (a.y.max(b.y).max(c.y).ceil() as u32).min(self.height);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let px = x as f32 + 0.5;
+                let py = y as f32 + 0.5;
+                let w0 = edge(b, c, px, py) / area;
+                let w1 = edge(c, a, px, py) / area;
+                let w2 = edge(a, b, px, py) / area;
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                let depth = w0 * a.depth + w1 * b.depth + w2 * c.depth;
+                if !(0.0..=1.0).contains(&depth) {
+                    continue;
+                }
+                let (near, far) = params.depth_range;
+                let depth = near + (far - near) * depth;
+                let i = (y * self.width + x) as usize;
+                let pass = match params.depth_test {
+                    DepthTest::Overwrite => true,
+                    DepthTest::IfLess => depth < self.depth[i],
+                    DepthTest::IfMore => depth > self.depth[i],
+                };
+                if !pass {
+                    continue;
+                }
+
+                // Perspective correct interpolation
+                let (p0, p1, p2) = (w0 * a.inv_w, w1 * b.inv_w, w2 * c.inv_w);
+                let sum = p0 + p1 + p2;
+                let position =
+                    (a.vertex.position * p0 + b.vertex.position * p1 + c.vertex.position * p2)
+                        / sum;
+                let normal =
+                    (a.vertex.normal * p0 + b.vertex.normal * p1 + c.vertex.normal * p2) / sum;
+                let color = [0, 1, 2].map(|j| {
+                    (a.vertex.color[j] * p0 + b.vertex.color[j] * p1 + c.vertex.color[j] * p2) / sum
+                });
+
+                if params.depth_write {
+                    self.depth[i] = depth;
+                }
+                self.color[i] = shade(position, normal, color);
+            }
+        }
+    }
+
+    // Clips the triangle and draws what is left of it
+    fn draw_clipped<S>(&mut self, tri: [ClipVertex; 3], params: DrawParams, shade: &S)
+    where
+        S: Fn(Vector3<f32>, Vector3<f32>, [Vector3<f32>; 3]) -> [f32; 4],
+    {
+        let poly = clip_near(tri);
+        for i in 2..poly.len() {
+            self.draw_triangle([&poly[0], &poly[i - 1], &poly[i]], params, shade);
+        }
+    }
+
+    // Average blocks of samples down to the final image size
+    fn resolve(&self, scale: u32) -> image::DynamicImage {
+        let width = self.width / scale;
+        let height = self.height / scale;
+        let samples = (scale * scale) as f32;
+        let img = image::RgbaImage::from_fn(width, height, |x, y| {
+            let mut sum = [0.0f32; 4];
+            for sy in 0..scale {
+                for sx in 0..scale {
+                    let i = ((y * scale + sy) * self.width + x * scale + sx) as usize;
+                    for (s, c) in sum.iter_mut().zip(self.color[i].iter()) {
+                        *s += c.clamp(0.0, 1.0);
+                    }
+                }
+            }
+            image::Rgba(sum.map(|s| (s / samples * 255.0).round() as u8))
+        });
+        image::DynamicImage::ImageRgba8(img)
+    }
+}
+
+fn edge(a: &ScreenVertex, b: &ScreenVertex, px: f32, py: f32) -> f32 {
+    (b.x - a.x) * (py - a.y) - (b.y - a.y) * (px - a.x)
+}
+
+// Clip a triangle against the near plane (z >= -w).
+// The other planes are handled by limiting the raster loop to the image.
+fn clip_near(tri: [ClipVertex; 3]) -> Vec<ClipVertex> {
+    let mut out = Vec::with_capacity(4);
+    for i in 0..3 {
+        let cur = &tri[i];
+        let next = &tri[(i + 1) % 3];
+        let d_cur = cur.clip.z + cur.clip.w;
+        let d_next = next.clip.z + next.clip.w;
+        if d_cur >= 0.0 {
+            out.push(*cur);
+        }
+        if (d_cur >= 0.0) != (d_next >= 0.0) {
+            out.push(cur.lerp(next, d_cur / (d_cur - d_next)));
+        }
+    }
+    out
+}
+
+// Vertex of something drawn in a single color, which is passed as the ambient color
+fn flat_vertex(clip: Vector4<f32>, color: [f32; 3]) -> ClipVertex {
+    let zero = Vector3::new(0.0, 0.0, 0.0);
+    ClipVertex {
+        clip,
+        position: zero,
+        normal: zero,
+        color: [Vector3::from(color), zero, zero],
+    }
+}
+
+pub fn render(
+    options: &RenderOptions,
+    mesh: &Mesh,
+    modelview: Matrix4<f32>,
+    perspective: Matrix4<f32>,
+    shadow: Option<Matrix4<f32>>,
+    floor: &[FloorVertex],
+    light_dir: [f32; 3],
+) -> image::DynamicImage {
+    let scale = match options.aamethod {
+        AAMethod::None => 1,
+        AAMethod::FXAA => SUPERSAMPLE,
+    };
+    let mut target = Target::new(
+        options.width * scale,
+        options.height * scale,
+        options.background,
+    );
+
+    // Same as mat3(modelview) in the vertex shader
+    let normal_matrix = Matrix3::from_cols(
+        modelview.x.truncate(),
+        modelview.y.truncate(),
+        modelview.z.truncate(),
+    );
+    let mvp = perspective * modelview;
+
+    // Flat shadow and floor under the model, see renderer::render_pipeline
+    if let Some(shadow) = shadow {
+        let shadow_mvp = mvp * shadow;
+        let color = shadow_color(options.background);
+        let params = DrawParams {
+            depth_write: true,
+            depth_range: (0.0, 0.0),
+            ..Default::default()
+        };
+        let vertices: Vec<ClipVertex> = mesh
+            .vertices
+            .iter()
+            .map(|v| flat_vertex(shadow_mvp * Vector3::from(v.position).extend(1.0), [0.0; 3]))
+            .collect();
+        for tri in mesh.triangles() {
+            target.draw_clipped(tri.map(|v| vertices[v]), params, &|_, _, _| color);
+        }
+    }
+    let mut floor_passes = vec![(DepthTest::IfLess, 1.0)];
+    if shadow.is_some() {
+        floor_passes.push((DepthTest::IfMore, 1.0 - SHADOW_OPACITY));
+    }
+    for (depth_test, brightness) in floor_passes {
+        let params = DrawParams {
+            depth_test,
+            ..Default::default()
+        };
+        let shade = |_, _, color: [Vector3<f32>; 3]| {
+            let c = color[0] * brightness;
+            [c.x, c.y, c.z, 1.0]
+        };
+        for verts in floor.chunks_exact(3) {
+            let tri = [0, 1, 2].map(|i| {
+                flat_vertex(
+                    mvp * Vector3::from(verts[i].position).extend(1.0),
+                    verts[i].color,
+                )
+            });
+            target.draw_clipped(tri, params, &shade);
+        }
+    }
+    // The model always sits on top of the ground
+    target.depth.fill(1.0);
+
+    let colors = vertex_colors(options, mesh);
+    let light = Vector3::from(light_dir).normalize();
+    let params = DrawParams {
+        cull_clockwise: true,
+        depth_test: DepthTest::IfLess,
+        depth_write: true,
+        ..Default::default()
+    };
+
+    let shade = |position: Vector3<f32>, normal: Vector3<f32>, color: [Vector3<f32>; 3]| {
+        let [ambient, diffuse_color, specular_color] = color;
+        let normal = normal.normalize();
+        let diffuse = normal.dot(light).max(0.0);
+        let camera_dir = (-position).normalize();
+        let half_direction = (light + camera_dir).normalize();
+        let specular = half_direction.dot(normal).max(0.0).powf(16.0);
+        let c = ambient + diffuse_color * diffuse + specular_color * specular;
+        [c.x, c.y, c.z, 1.0]
+    };
+
+    let vertices: Vec<ClipVertex> = mesh
+        .vertices
+        .iter()
+        .zip(&mesh.normals)
+        .zip(&colors)
+        .map(|((v, n), m)| {
+            let p = Vector3::from(v.position).extend(1.0);
+            ClipVertex {
+                clip: mvp * p,
+                position: (modelview * p).truncate(),
+                normal: normal_matrix * Vector3::from(n.normal),
+                color: [m.ambient, m.diffuse, m.specular].map(Vector3::from),
+            }
+        })
+        .collect();
+    for tri in mesh.triangles() {
+        target.draw_clipped(tri.map(|v| vertices[v]), params, &shade);
+    }
+
+    target.resolve(scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{scene_matrices, shadow_matrix, LIGHT_DIR};
+
+    #[test]
+    fn cube_fills_center() {
+        let options = RenderOptions {
+            width: 64,
+            height: 48,
+            ..Default::default()
+        };
+        let mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        let (modelview, perspective) = scene_matrices(&options, &mesh);
+        let img = render(
+            &options,
+            &mesh,
+            modelview,
+            perspective,
+            None,
+            &[],
+            LIGHT_DIR,
+        )
+        .to_rgba8();
+
+        assert_eq!((64, 48), img.dimensions());
+        // Model is centered, corners are left as background
+        assert_eq!(255, img.get_pixel(32, 24)[3]);
+        assert_eq!(0, img.get_pixel(0, 0)[3]);
+        assert_eq!(0, img.get_pixel(63, 47)[3]);
+    }
+
+    #[test]
+    fn shadow_on_ground() {
+        let mut options = RenderOptions {
+            width: 64,
+            height: 48,
+            aamethod: AAMethod::None,
+            shadow: true,
+            ..Default::default()
+        };
+        let mesh = Mesh::load("test_data/cube.stl", false).unwrap();
+        let shadow_pixels = |options: &RenderOptions| {
+            let (modelview, perspective) = scene_matrices(options, &mesh);
+            let shadow = shadow_matrix(options, &mesh);
+            let img = render(
+                options,
+                &mesh,
+                modelview,
+                perspective,
+                shadow,
+                &[],
+                LIGHT_DIR,
+            );
+            let color = shadow_color(options.background).map(|c| (c * 255.0).round() as u8);
+            img.to_rgba8().pixels().filter(|p| p.0 == color).count()
+        };
+
+        assert!(shadow_pixels(&options) > 50);
+        // Looking up from below the ground hides the shadow
+        options.camera.elevation = -30.0;
+        assert_eq!(0, shadow_pixels(&options));
+    }
+}
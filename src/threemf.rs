@@ -0,0 +1,547 @@
+// 3MF Reader
+// ==========
+//
+// Reads the build of a 3MF package. Only the objects referenced by build items are
+// instantiated, with component trees resolved recursively and all transforms applied,
+// so parts end up where the slicer placed them on the plate.
+//
+// Components may point into other model parts of the package (the production extension,
+// used by PrusaSlicer and Bambu Studio), so model parts are loaded as they are referenced.
+//
+// Colors come from basematerials and m:colorgroup resources, referenced by the pid and
+// pindex of an object or the pid and p1/p2/p3 of a single triangle. Other property
+// resources such as textures are not supported and leave the triangle uncolored.
+
+use crate::error::Error;
+use cgmath::{Matrix4, SquareMatrix};
+use quick_xml::events::{BytesStart, Event};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Read, Seek};
+
+// Part used when the package relationships don't name one
+const DEFAULT_MODEL: &str = "3D/3dmodel.model";
+const MODEL_RELATIONSHIP: &str = "http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel";
+
+// Components can reference a shared object many times on each level, so a small file can expand
+// into more than fits in memory. Builds with more instances, or more triangles copied from objects
+// that were already instantiated, are refused. The first instance of each object is not limited,
+// large meshes are left to decimation like in other formats.
+const MAX_INSTANCES: usize = 100_000;
+const MAX_COPIED_TRIANGLES: usize = 10_000_000;
+
+/// A triangle of the build, in build coordinates.
+pub struct Triangle {
+    pub vertices: [[f32; 3]; 3],
+    // Color of each corner as sRGB, None if the file doesn't specify one
+    pub colors: [Option<[f32; 3]>; 3],
+}
+
+// Triangle of an object as it appears in the file
+struct ObjectTriangle {
+    vertices: [usize; 3],
+    // Property group and the index into it for each corner
+    pid: Option<usize>,
+    p: [Option<usize>; 3],
+}
+
+struct Component {
+    // Model part containing the object, None for the part of the referencing object
+    path: Option<String>,
+    object_id: usize,
+    transform: Matrix4<f32>,
+}
+
+#[derive(Default)]
+struct Object {
+    vertices: Vec<[f32; 3]>,
+    triangles: Vec<ObjectTriangle>,
+    components: Vec<Component>,
+    // Default property for triangles that don't have their own
+    pid: Option<usize>,
+    pindex: Option<usize>,
+}
+
+// Triangles of the build so far, the objects being instantiated and those that were before
+#[derive(Default)]
+struct Build {
+    triangles: Vec<Triangle>,
+    ancestors: HashSet<(String, usize)>,
+    instantiated: HashSet<(String, usize)>,
+    instances: usize,
+    copied_triangles: usize,
+}
+
+struct Item {
+    object_id: usize,
+    transform: Matrix4<f32>,
+}
+
+#[derive(Default)]
+struct Model {
+    objects: HashMap<usize, Object>,
+    // Colors of the basematerials and colorgroup resources
+    properties: HashMap<usize, Vec<[f32; 3]>>,
+    build: Vec<Item>,
+}
+
+fn invalid<E: ToString>(message: E) -> Error {
+    Error::parse("3MF", message.to_string())
+}
+
+pub fn read<R: Read + Seek>(model_file: R) -> Result<Vec<Triangle>, Error> {
+    let mut zip = zip::ZipArchive::new(model_file).map_err(invalid)?;
+    let root = root_model_path(&mut zip);
+    let mut models = HashMap::new();
+    load_model(&mut zip, &mut models, &root)?;
+
+    let mut build = Build::default();
+    let items: Vec<(usize, Matrix4<f32>)> = {
+        let model = &models[&root];
+        if model.build.is_empty() {
+            // Not valid 3MF, but better to show every object than nothing at all
+            warn!("3MF file has no build items");
+            let mut ids: Vec<usize> = model.objects.keys().copied().collect();
+            ids.sort_unstable();
+            ids.into_iter()
+                .map(|id| (id, Matrix4::identity()))
+                .collect()
+        } else {
+            model
+                .build
+                .iter()
+                .map(|item| (item.object_id, item.transform))
+                .collect()
+        }
+    };
+    for (object_id, transform) in items {
+        add_object(
+            &mut zip,
+            &mut models,
+            &root,
+            object_id,
+            transform,
+            &mut build,
+        )?;
+    }
+
+    Ok(build.triangles)
+}
+
+// Instantiate an object and all of its components
+fn add_object<R: Read + Seek>(
+    zip: &mut zip::ZipArchive<R>,
+    models: &mut HashMap<String, Model>,
+    path: &str,
+    object_id: usize,
+    transform: Matrix4<f32>,
+    build: &mut Build,
+) -> Result<(), Error> {
+    let key = (path.to_string(), object_id);
+    if !build.ancestors.insert(key.clone()) {
+        return Err(invalid("Components contain a cycle"));
+    }
+    build.instances += 1;
+    if build.instances > MAX_INSTANCES {
+        return Err(invalid(format!(
+            "Build has more than {} object instances",
+            MAX_INSTANCES
+        )));
+    }
+
+    let model = &models[path];
+    let object = model
+        .objects
+        .get(&object_id)
+        .ok_or_else(|| invalid(format!("Object {} not found in {}", object_id, path)))?;
+
+    if !build.instantiated.insert(key.clone()) {
+        build.copied_triangles += object.triangles.len();
+        if build.copied_triangles > MAX_COPIED_TRIANGLES {
+            return Err(invalid(format!(
+                "Build copies more than {} triangles",
+                MAX_COPIED_TRIANGLES
+            )));
+        }
+    }
+
+    // Mirroring transforms turn the triangles inside out
+    let flip = transform.determinant() < 0.0;
+    for tri in &object.triangles {
+        let mut vertices = [[0.0; 3]; 3];
+        for (v, &i) in vertices.iter_mut().zip(tri.vertices.iter()) {
+            let p = object
+                .vertices
+                .get(i)
+                .ok_or_else(|| invalid(format!("Vertex index {} out of range", i)))?;
+            let p = transform * cgmath::Vector3::from(*p).extend(1.0);
+            *v = [p.x, p.y, p.z];
+        }
+        // p1 applies to the whole triangle unless p2 and p3 are given as well
+        let (pid, p) = match (tri.pid, tri.p) {
+            (Some(pid), [Some(p1), p2, p3]) => {
+                (Some(pid), [p1, p2.unwrap_or(p1), p3.unwrap_or(p1)])
+            }
+            // Without p1 the triangle uses the object's pindex, even with its own pid
+            (pid, _) => match (pid.or(object.pid), object.pindex) {
+                (Some(pid), Some(pindex)) => (Some(pid), [pindex; 3]),
+                _ => (None, [0; 3]),
+            },
+        };
+        let group = pid.and_then(|pid| model.properties.get(&pid));
+        let mut colors = p.map(|i| group.and_then(|g| g.get(i)).copied());
+        if flip {
+            vertices.swap(1, 2);
+            colors.swap(1, 2);
+        }
+        build.triangles.push(Triangle { vertices, colors });
+    }
+
+    let components: Vec<(String, usize, Matrix4<f32>)> = object
+        .components
+        .iter()
+        .map(|c| {
+            let path = c.path.clone().unwrap_or_else(|| path.to_string());
+            (path, c.object_id, transform * c.transform)
+        })
+        .collect();
+    for (path, object_id, transform) in components {
+        load_model(zip, models, &path)?;
+        add_object(zip, models, &path, object_id, transform, build)?;
+    }
+
+    build.ancestors.remove(&key);
+    Ok(())
+}
+
+// Find the main model part from the package relationships
+fn root_model_path<R: Read + Seek>(zip: &mut zip::ZipArchive<R>) -> String {
+    let mut rels = String::new();
+    if let Ok(mut file) = zip.by_name("_rels/.rels") {
+        if file.read_to_string(&mut rels).is_err() {
+            rels.clear();
+        }
+    }
+    let mut reader = quick_xml::Reader::from_str(&rels);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e))
+                if e.local_name().as_ref() == b"Relationship"
+                    && attribute(&e, b"Type").as_deref() == Some(MODEL_RELATIONSHIP) =>
+            {
+                if let Some(target) = attribute(&e, b"Target") {
+                    return normalize_path(&target);
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+    }
+    DEFAULT_MODEL.to_string()
+}
+
+// Part names are absolute URIs inside the package, zip entries have no leading slash
+fn normalize_path(path: &str) -> String {
+    path.trim_start_matches('/').to_string()
+}
+
+fn load_model<R: Read + Seek>(
+    zip: &mut zip::ZipArchive<R>,
+    models: &mut HashMap<String, Model>,
+    path: &str,
+) -> Result<(), Error> {
+    if models.contains_key(path) {
+        return Ok(());
+    }
+    let file = zip
+        .by_name(path)
+        .map_err(|e| invalid(format!("{}: {}", path, e)))?;
+    let model = parse_model(BufReader::new(file))?;
+    models.insert(path.to_string(), model);
+    Ok(())
+}
+
+fn parse_model<R: std::io::BufRead>(input: R) -> Result<Model, Error> {
+    let mut reader = quick_xml::Reader::from_reader(input);
+    let mut buf = Vec::new();
+    let mut model = Model::default();
+    // Object and property group currently being read
+    let mut object: Option<(usize, Object)> = None;
+    let mut group: Option<(usize, Vec<[f32; 3]>)> = None;
+
+    loop {
+        let event = reader.read_event_into(&mut buf).map_err(invalid)?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                match (e.local_name().as_ref(), object.as_mut()) {
+                    (b"object", _) => {
+                        let id =
+                            number(e, b"id")?.ok_or_else(|| invalid("Object without an id"))?;
+                        object = Some((
+                            id,
+                            Object {
+                                pid: number(e, b"pid")?,
+                                pindex: number(e, b"pindex")?,
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                    (b"basematerials", _) | (b"colorgroup", _) => {
+                        let id = number(e, b"id")?
+                            .ok_or_else(|| invalid("Property group without an id"))?;
+                        group = Some((id, Vec::new()));
+                    }
+                    (b"base", _) | (b"color", _) => {
+                        let name: &[u8] = if e.local_name().as_ref() == b"base" {
+                            b"displaycolor"
+                        } else {
+                            b"color"
+                        };
+                        if let Some((_, colors)) = group.as_mut() {
+                            let value = attribute(e, name).unwrap_or_default();
+                            colors.push(parse_color(&value)?);
+                        }
+                    }
+                    (b"vertex", Some((_, object))) => {
+                        let mut v = [0.0; 3];
+                        for (v, name) in v.iter_mut().zip([b"x", b"y", b"z"]) {
+                            *v = number(e, name)?
+                                .ok_or_else(|| invalid("Vertex without coordinates"))?;
+                        }
+                        object.vertices.push(v);
+                    }
+                    (b"triangle", Some((_, object))) => {
+                        let mut t = [0; 3];
+                        for (t, name) in t.iter_mut().zip([b"v1", b"v2", b"v3"]) {
+                            *t = number(e, name)?
+                                .ok_or_else(|| invalid("Triangle without vertices"))?;
+                        }
+                        object.triangles.push(ObjectTriangle {
+                            vertices: t,
+                            pid: number(e, b"pid")?,
+                            p: [number(e, b"p1")?, number(e, b"p2")?, number(e, b"p3")?],
+                        });
+                    }
+                    (b"component", Some((_, object))) => object.components.push(Component {
+                        path: attribute(e, b"path").map(|p| normalize_path(&p)),
+                        object_id: number(e, b"objectid")?
+                            .ok_or_else(|| invalid("Component without an objectid"))?,
+                        transform: transform(e)?,
+                    }),
+                    (b"item", _) => model.build.push(Item {
+                        object_id: number(e, b"objectid")?
+                            .ok_or_else(|| invalid("Build item without an objectid"))?,
+                        transform: transform(e)?,
+                    }),
+                    _ => (),
+                }
+                // Objects are never empty elements in valid files, but don't lose them if they are
+                if matches!(event, Event::Empty(_)) && e.local_name().as_ref() == b"object" {
+                    if let Some((id, o)) = object.take() {
+                        model.objects.insert(id, o);
+                    }
+                }
+            }
+            Event::End(ref e) if e.local_name().as_ref() == b"object" => {
+                if let Some((id, o)) = object.take() {
+                    model.objects.insert(id, o);
+                }
+            }
+            Event::End(ref e)
+                if matches!(e.local_name().as_ref(), b"basematerials" | b"colorgroup") =>
+            {
+                if let Some((id, colors)) = group.take() {
+                    model.properties.insert(id, colors);
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(model)
+}
+
+// Value of an attribute, ignoring its namespace prefix
+fn attribute(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name)
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+fn number<T: std::str::FromStr>(e: &BytesStart, name: &[u8]) -> Result<Option<T>, Error> {
+    match attribute(e, name) {
+        Some(value) => value.trim().parse().map(Some).map_err(|_| {
+            invalid(format!(
+                "Invalid {} {:?}",
+                String::from_utf8_lossy(name),
+                value
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+// sRGB color as #RRGGBB or #RRGGBBAA. Alpha is ignored.
+fn parse_color(value: &str) -> Result<[f32; 3], Error> {
+    let hex = value.trim().trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+            .map(|c| c as f32 / 255.0)
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6 | 8, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(invalid(format!("Invalid color {:?}", value))),
+    }
+}
+
+// 3MF transforms are the first three columns of a 4x4 matrix for row vectors, listed row by row
+fn transform(e: &BytesStart) -> Result<Matrix4<f32>, Error> {
+    let value = match attribute(e, b"transform") {
+        Some(value) => value,
+        None => return Ok(Matrix4::identity()),
+    };
+    let m: Vec<f32> = value
+        .split_ascii_whitespace()
+        .map(|n| n.parse::<f32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(format!("Invalid transform {:?}", value)))?;
+    if m.len() != 12 {
+        return Err(invalid(format!("Invalid transform {:?}", value)));
+    }
+    // Transposed into cgmath's column vector convention
+    #[rustfmt::skip]
+    let matrix = Matrix4::new(
+        m[0], m[1], m[2], 0.0,
+        m[3], m[4], m[5], 0.0,
+        m[6], m[7], m[8], 0.0,
+        m[9], m[10], m[11], 1.0,
+    );
+    Ok(matrix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn package(parts: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in parts {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let mut data = zip.finish().unwrap();
+        data.set_position(0);
+        data
+    }
+
+    const TRIANGLE: &str = r#"<mesh>
+        <vertices>
+            <vertex x="0" y="0" z="0"/><vertex x="1" y="0" z="0"/><vertex x="0" y="1" z="0"/>
+        </vertices>
+        <triangles><triangle v1="0" v2="1" v3="2"/></triangles>
+    </mesh>"#;
+
+    #[test]
+    fn build_items_and_components() {
+        let model = format!(
+            r#"<model xmlns:p="http://schemas.microsoft.com/3dmanufacturing/production/2015/06">
+            <resources>
+                <object id="1">{}</object>
+                <object id="2"><mesh><vertices/><triangles/></mesh></object>
+                <object id="3"><components>
+                    <component objectid="1" transform="1 0 0 0 1 0 0 0 1 0 0 5"/>
+                    <component objectid="7" p:path="/3D/Objects/part.model"/>
+                </components></object>
+            </resources>
+            <build><item objectid="3" transform="2 0 0 0 2 0 0 0 2 10 0 0"/></build>
+            </model>"#,
+            TRIANGLE
+        );
+        let part = format!(
+            r#"<model><resources><object id="7">{}</object></resources><build/></model>"#,
+            TRIANGLE
+        );
+        let triangles = read(package(&[
+            (DEFAULT_MODEL, &model),
+            ("3D/Objects/part.model", &part),
+        ]))
+        .unwrap();
+
+        // Object 1 is only used through object 3, object 2 is not in the build
+        assert_eq!(2, triangles.len());
+        assert_eq!([10.0, 0.0, 10.0], triangles[0].vertices[0]);
+        assert_eq!([12.0, 0.0, 10.0], triangles[0].vertices[1]);
+        assert_eq!([10.0, 0.0, 0.0], triangles[1].vertices[0]);
+    }
+
+    #[test]
+    fn colors() {
+        let model = r##"<model xmlns:m="http://schemas.microsoft.com/3dmanufacturing/material/2015/02">
+            <resources>
+                <basematerials id="1">
+                    <base name="Red" displaycolor="#FF0000"/><base name="Blue" displaycolor="#0000FFFF"/>
+                </basematerials>
+                <m:colorgroup id="2"><m:color color="#00FF00"/><m:color color="#FFFFFF"/></m:colorgroup>
+                <object id="3" pid="1" pindex="1"><mesh>
+                    <vertices>
+                        <vertex x="0" y="0" z="0"/><vertex x="1" y="0" z="0"/><vertex x="0" y="1" z="0"/>
+                    </vertices>
+                    <triangles>
+                        <triangle v1="0" v2="1" v3="2"/>
+                        <triangle v1="0" v2="1" v3="2" pid="1" p1="0"/>
+                        <triangle v1="0" v2="1" v3="2" pid="2" p1="0" p2="1" p3="1"/>
+                    </triangles>
+                </mesh></object>
+            </resources>
+            <build><item objectid="3"/></build>
+            </model>"##;
+        let triangles = read(package(&[(DEFAULT_MODEL, model)])).unwrap();
+
+        let blue = Some([0.0, 0.0, 1.0]);
+        let red = Some([1.0, 0.0, 0.0]);
+        let green = Some([0.0, 1.0, 0.0]);
+        let white = Some([1.0, 1.0, 1.0]);
+        assert_eq!([blue; 3], triangles[0].colors);
+        assert_eq!([red; 3], triangles[1].colors);
+        assert_eq!([green, white, white], triangles[2].colors);
+    }
+
+    #[test]
+    fn component_cycle() {
+        let model = r#"<model><resources>
+            <object id="1"><components><component objectid="2"/></components></object>
+            <object id="2"><components><component objectid="1"/></components></object>
+            </resources><build><item objectid="1"/></build></model>"#;
+        assert!(matches!(
+            read(package(&[(DEFAULT_MODEL, model)])),
+            Err(Error::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn component_explosion() {
+        // Each level uses the one below ten times, 10^12 instances of the triangle in all
+        let mut objects = format!(r#"<object id="1">{}</object>"#, TRIANGLE);
+        for id in 2..=13 {
+            let component = format!(r#"<component objectid="{}"/>"#, id - 1);
+            objects += &format!(
+                r#"<object id="{}"><components>{}</components></object>"#,
+                id,
+                component.repeat(10)
+            );
+        }
+        let model = format!(
+            r#"<model><resources>{}</resources><build><item objectid="13"/></build></model>"#,
+            objects
+        );
+        assert!(matches!(
+            read(package(&[(DEFAULT_MODEL, &model)])),
+            Err(Error::Parse { .. })
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+// Freedesktop Thumbnails
+// ======================
+//
+// File managers cache thumbnails and check them against the file they show before using
+// them, with details of that file stored in text chunks of the PNG.
+// https://specifications.freedesktop.org/thumbnail-spec/latest/
+
+use crate::error::Error;
+use crate::format::ModelFormat;
+use crate::mesh::Mesh;
+use crate::uri;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Details of the model file a thumbnail was made from.
+pub(crate) struct ThumbInfo {
+    pub uri: String,
+    // Modification time in seconds since the epoch
+    pub mtime: u64,
+    pub size: u64,
+    pub mime_type: &'static str,
+}
+
+impl ThumbInfo {
+    /// Reads the details of a model file, given as a file name or URI. `format` overrides
+    /// detection, like when loading it.
+    pub(crate) fn for_model(name: &str, format: Option<ModelFormat>) -> Result<ThumbInfo, Error> {
+        let path = uri::model_path(name)?;
+        let format = match format {
+            Some(format) => format,
+            None => Mesh::detect_file(&mut File::open(&path)?, &path)?,
+        };
+        ThumbInfo::for_file(name, mime_type(format, &path))
+    }
+
+    /// Reads the details of any file, with the MIME type given instead of detected.
+    pub(crate) fn for_file(name: &str, mime_type: &'static str) -> Result<ThumbInfo, Error> {
+        let path = uri::model_path(name)?;
+        let metadata = fs::metadata(&path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(ThumbInfo {
+            // URIs given by the file manager are escaped again, text chunks only hold Latin-1
+            uri: uri::file_uri(&path)?,
+            mtime,
+            size: metadata.len(),
+            mime_type,
+        })
+    }
+
+    /// Keywords and text of the PNG text chunks.
+    pub(crate) fn text_chunks(&self) -> [(&'static str, String); 5] {
+        [
+            ("Thumb::URI", self.uri.clone()),
+            ("Thumb::MTime", self.mtime.to_string()),
+            ("Thumb::Size", self.size.to_string()),
+            ("Thumb::Mimetype", self.mime_type.to_string()),
+            (
+                "Software",
+                format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            ),
+        ]
+    }
+}
+
+fn mime_type(format: ModelFormat, path: &Path) -> &'static str {
+    match format {
+        ModelFormat::Stl => "model/stl",
+        ModelFormat::Obj => "model/obj",
+        ModelFormat::ThreeMf => "model/3mf",
+        // PLY has no registered type
+        ModelFormat::Ply => "model/x-ply",
+        ModelFormat::Gltf => match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("glb") => "model/gltf-binary",
+            _ => "model/gltf+json",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_file() {
+        let info = ThumbInfo::for_model("test_data/cube.gltf", None).unwrap();
+        assert_eq!("model/gltf+json", info.mime_type);
+        assert_eq!(
+            std::fs::metadata("test_data/cube.gltf").unwrap().len(),
+            info.size
+        );
+        assert!(info.mtime > 0);
+    }
+
+    #[test]
+    fn non_ascii_uri() {
+        let path =
+            std::env::temp_dir().join(format!("stl-thumb-würfel-{}.stl", std::process::id()));
+        std::fs::copy("test_data/cube.stl", &path).unwrap();
+        let info = ThumbInfo::for_model(&format!("file://{}", path.display()), None);
+        std::fs::remove_file(&path).unwrap();
+
+        let info = info.unwrap();
+        assert!(info.uri.is_ascii());
+        assert!(info.uri.contains("w%C3%BCrfel"));
+        let img = image::DynamicImage::new_rgba8(4, 4);
+        crate::encode_image(&img, &image::ImageFormat::Png, Some(&info)).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+// File URIs
+// =========
+//
+// Thumbnailers can be given URIs instead of file names (%u instead of %i), and the
+// thumbnail spec identifies files by their URI. Only local files can be read.
+
+use crate::error::Error;
+use std::path::{self, Path, PathBuf};
+
+// Characters GLib leaves as they are in file URIs, besides letters and digits
+const URI_SAFE: &[u8] = b"!$&'()*+,-./:=@_~";
+
+/// file:// URI of a path. It is escaped the same way as GLib's g_filename_to_uri, because
+/// file managers look up thumbnails by the exact URI.
+pub(crate) fn file_uri(path: &Path) -> Result<String, Error> {
+    let path = path::absolute(path)?;
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    // Windows paths become file:///C:/dir/file
+    #[cfg(not(unix))]
+    let bytes = format!("/{}", path.to_string_lossy().replace('\\', "/")).into_bytes();
+
+    let mut uri = String::from("file://");
+    for b in bytes {
+        if b.is_ascii_alphanumeric() || URI_SAFE.contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Ok(uri)
+}
+
+// Scheme of a URI such as file:///a.stl or sftp://host/a.stl. Paths like C:\a.stl have
+// single letter drives, which are not taken for schemes.
+fn scheme(name: &str) -> Option<&str> {
+    let (scheme, rest) = name.split_once(':')?;
+    let valid = scheme.len() > 1
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    // file:/a.stl is a URI too, other schemes only count with an authority
+    let is_file = scheme.eq_ignore_ascii_case("file");
+    (valid && (is_file || rest.starts_with("//"))).then_some(scheme)
+}
+
+/// Path of a model given either as a file name or a file:// URI.
+pub(crate) fn model_path(name: &str) -> Result<PathBuf, Error> {
+    let scheme = match scheme(name) {
+        Some(scheme) => scheme,
+        None => return Ok(PathBuf::from(name)),
+    };
+    if !scheme.eq_ignore_ascii_case("file") {
+        return Err(Error::InvalidUri(format!(
+            "{}: only file:// URIs are supported",
+            name
+        )));
+    }
+
+    let rest = &name[scheme.len() + 1..];
+    // Query and fragment don't name a file, GLib escapes ? and # in file names
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let path = match rest.strip_prefix("//") {
+        Some(authority) => {
+            let (host, path) = authority.split_at(authority.find('/').unwrap_or(authority.len()));
+            if !(host.is_empty() || host.eq_ignore_ascii_case("localhost")) {
+                return Err(Error::InvalidUri(format!(
+                    "{}: files on other hosts can't be read",
+                    name
+                )));
+            }
+            path
+        }
+        None => rest,
+    };
+    if !path.starts_with('/') {
+        return Err(Error::InvalidUri(format!("{}: path is not absolute", name)));
+    }
+
+    let bytes = percent_decode(path)
+        .ok_or_else(|| Error::InvalidUri(format!("{}: invalid percent encoding", name)))?;
+    #[cfg(unix)]
+    let path = {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    };
+    #[cfg(not(unix))]
+    let path = {
+        let path = String::from_utf8(bytes)
+            .map_err(|_| Error::InvalidUri(format!("{}: path is not valid unicode", name)))?;
+        // /C:/dir/file is C:/dir/file
+        match path.get(1..3) {
+            Some(drive) if drive.ends_with(':') => PathBuf::from(&path[1..]),
+            _ => PathBuf::from(path),
+        }
+    };
+    Ok(path)
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn escaped_uri() {
+        let path = Path::new("/tmp/My Models/café#(2).stl");
+        let uri = file_uri(path).unwrap();
+        assert_eq!("file:///tmp/My%20Models/caf%C3%A9%23(2).stl", uri);
+        // And back again
+        assert_eq!(path, model_path(&uri).unwrap());
+        let relative = file_uri(Path::new("test_data/cube.stl")).unwrap();
+        assert!(relative.starts_with("file:///") && relative.ends_with("/test_data/cube.stl"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_uris() {
+        for uri in [
+            "file:///tmp/a%20b.stl",
+            "FILE://localhost/tmp/a%20b.stl",
+            "file:/tmp/a%20b.stl",
+            "file:///tmp/a%20b.stl#fragment",
+        ] {
+            assert!(scheme(uri).is_some());
+            assert_eq!(
+                Path::new("/tmp/a b.stl"),
+                model_path(uri).unwrap(),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn plain_paths() {
+        for name in ["a.stl", "dir/a:b.stl", "C:\\models\\a.stl", "-"] {
+            assert!(scheme(name).is_none());
+            assert_eq!(Path::new(name), model_path(name).unwrap());
+        }
+    }
+
+    #[test]
+    fn unsupported_uris() {
+        for uri in [
+            "sftp://host/a.stl",
+            "file://host/a.stl",
+            "file:a.stl",
+            "file:///a%2.stl",
+        ] {
+            assert!(
+                matches!(model_path(uri), Err(Error::InvalidUri(_))),
+                "{}",
+                uri
+            );
+        }
+    }
+}